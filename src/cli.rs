@@ -0,0 +1,445 @@
+use markdawn::config::{Config, Overrides};
+use markdawn::data::Document;
+use markdawn::file_types::{FileKind, FileTypes};
+use markdawn::filter::PathFilter;
+use markdawn::include;
+use markdawn::parser::{self, ParseOptions};
+use markdawn::renderer::{self, RenderOptions};
+use markdawn::watcher;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Real-time markdown renderer with live browser preview
+#[derive(Parser, Debug)]
+#[command(name = "markdawn", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the preview server and watch PATH for changes
+    Serve(ServeArgs),
+    /// Render a single markdown file to HTML
+    Render(RenderArgs),
+    /// Render every markdown file under DIR into static HTML pages
+    Build(BuildArgs),
+    /// Parse every markdown file under PATH and report errors
+    Check(CheckArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Directories or single markdown files to watch; with more than one,
+    /// each is served under its own name
+    #[arg(default_value = ".", value_name = "PATH")]
+    pub paths: Vec<PathBuf>,
+
+    /// Address to bind the HTTP server to [default: 127.0.0.1]
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port to bind the HTTP server to [default: 5000]
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Open the preview in the default browser once the server is up
+    #[arg(long)]
+    pub open: bool,
+
+    /// Quiet period after a file's last change before it is rendered; a file that keeps
+    /// changing is still rendered every max_wait_ms [default: 300]
+    #[arg(long, value_name = "MS")]
+    pub debounce_ms: Option<u64>,
+
+    /// Directory served under /static [default: static]
+    #[arg(long, value_name = "DIR")]
+    pub static_dir: Option<PathBuf>,
+
+    /// Serve without watching the filesystem
+    #[arg(long)]
+    pub no_watch: bool,
+
+    /// Poll for changes instead of using native file events, e.g. on
+    /// network filesystems
+    #[arg(long)]
+    pub poll: bool,
+
+    /// How often to poll [default: 1000]
+    #[arg(long, value_name = "MS")]
+    pub poll_interval_ms: Option<u64>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+impl Default for ServeArgs {
+    fn default() -> Self {
+        Self {
+            paths: vec![PathBuf::from(".")],
+            host: None,
+            port: None,
+            open: false,
+            debounce_ms: None,
+            static_dir: None,
+            no_watch: false,
+            poll: false,
+            poll_interval_ms: None,
+            filter: FilterArgs::default(),
+        }
+    }
+}
+
+impl ServeArgs {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            host: self.host.clone(),
+            port: self.port,
+            static_dir: self.static_dir.clone(),
+            debounce_ms: self.debounce_ms,
+            poll: self.poll,
+            poll_interval_ms: self.poll_interval_ms,
+            include: self.filter.include.clone(),
+            exclude: self.filter.ignore.clone(),
+            no_ignore: self.filter.no_ignore,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RenderArgs {
+    /// Markdown file to render, or - to read from stdin
+    pub file: PathBuf,
+
+    /// Write the output here instead of stdout (- also means stdout)
+    #[arg(short, long, value_name = "OUT")]
+    pub output: Option<PathBuf>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Html)]
+    pub format: OutputFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// HTML fragment
+    Html,
+    /// Standalone HTML page using the configured template
+    Page,
+    /// Parsed document tree as JSON
+    Json,
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+#[derive(Args, Debug)]
+pub struct BuildArgs {
+    /// Directory containing markdown files
+    pub dir: PathBuf,
+
+    /// Directory the HTML pages are written to
+    #[arg(short, long, value_name = "DIR", default_value = "dist")]
+    pub out_dir: PathBuf,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    /// File or directory to check
+    #[arg(default_value = ".")]
+    pub path: PathBuf,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct FilterArgs {
+    /// Skip paths matching this glob (repeatable), e.g. --ignore 'target/**'
+    #[arg(long = "ignore", value_name = "GLOB")]
+    pub ignore: Vec<String>,
+
+    /// Only use markdown files matching this glob (repeatable), e.g. --include 'docs/**'
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Don't read .gitignore/.markdawnignore files or skip .git, node_modules and target
+    #[arg(long)]
+    pub no_ignore: bool,
+}
+
+impl FilterArgs {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            include: self.include.clone(),
+            exclude: self.ignore.clone(),
+            no_ignore: self.no_ignore,
+            ..Overrides::default()
+        }
+    }
+}
+
+// discover() has already validated the extension names
+fn parse_options(config: &Config) -> ParseOptions {
+    config.markdown.parse_options().unwrap_or_default()
+}
+
+// Loads markdawn.toml from root with the command-line overrides applied
+fn load_config(root: &Path, overrides: &Overrides) -> Result<(Config, PathFilter), ExitCode> {
+    let mut config = Config::discover(root).map_err(|e| {
+        eprintln!("{}", e);
+        ExitCode::FAILURE
+    })?;
+    config.apply(overrides);
+
+    match config.watch.filter_for(root) {
+        Ok(filter) => Ok((config, filter)),
+        Err(e) => {
+            eprintln!("Invalid --include/--ignore pattern: {}", e);
+            Err(ExitCode::from(2))
+        }
+    }
+}
+
+pub fn render(args: RenderArgs) -> ExitCode {
+    let from_stdin = is_stdio(&args.file);
+    let source_name = if from_stdin {
+        "<stdin>".to_string()
+    } else {
+        args.file.display().to_string()
+    };
+
+    let mut content = String::new();
+    let read_result = if from_stdin {
+        std::io::stdin().read_to_string(&mut content).map(|_| ())
+    } else {
+        std::fs::read_to_string(&args.file).map(|text| content = text)
+    };
+    if let Err(e) = read_result {
+        eprintln!("Failed to read {}: {}", source_name, e);
+        return ExitCode::FAILURE;
+    }
+
+    // stdin has no root of its own, so look for markdawn.toml in the working directory
+    let root = if from_stdin { Path::new(".") } else { args.file.as_path() };
+    let (config, _) = match load_config(root, &Overrides::default()) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+
+    let document = match parse_file(&args.file, &content, &config.watch.file_types(), &parse_options(&config)) {
+        Ok(document) => document,
+        Err(e) => {
+            eprintln!("Parse error in {}: {}", source_name, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // json shows the document as parsed, with its includes
+    let expanded = || {
+        let file_types = config.watch.file_types();
+        expand_includes(&args.file, document.clone(), &file_types, &parse_options(&config))
+    };
+    let output = match args.format {
+        OutputFormat::Html => expanded().to_html_with(&config.render),
+        OutputFormat::Page => {
+            let template = match config.page_template() {
+                Ok(template) => template.unwrap_or_else(|| renderer::DEFAULT_PAGE_TEMPLATE.to_string()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let title = document.title().unwrap_or(source_name);
+            renderer::render_page(&template, &title, &config.ui.theme, &expanded().to_html_with(&config.render))
+        }
+        OutputFormat::Json => match serde_json::to_string_pretty(&document) {
+            Ok(json) => json,
+            Err(e) => {
+                eprintln!("Failed to serialize document: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    match args.output.filter(|path| !is_stdio(path)) {
+        Some(path) => {
+            if let Err(e) = std::fs::write(&path, output) {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+        None => {
+            // a closed pipe (e.g. `| head`) is not an error for a filter
+            let mut stdout = std::io::stdout().lock();
+            if let Err(e) = writeln!(stdout, "{}", output)
+                && e.kind() != std::io::ErrorKind::BrokenPipe
+            {
+                eprintln!("Failed to write output: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+pub fn build(args: BuildArgs) -> ExitCode {
+    let (config, filter) = match load_config(&args.dir, &args.filter.overrides()) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    let template = match config.page_template() {
+        Ok(template) => template.unwrap_or_else(|| renderer::DEFAULT_PAGE_TEMPLATE.to_string()),
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let file_types = config.watch.file_types();
+    let page = PageSettings {
+        parse_options: parse_options(&config),
+        file_types: &file_types,
+        template: &template,
+        theme: &config.ui.theme,
+        options: &config.render,
+    };
+
+    let files = match watcher::find_markdown_files(&args.dir, &filter, &file_types) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Failed to scan {}: {}", args.dir.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failures = 0;
+    for file in &files {
+        if let Err(e) = build_page(file, &args.dir, &args.out_dir, &page) {
+            eprintln!("{}: {}", file.display(), e);
+            failures += 1;
+        }
+    }
+
+    println!(
+        "Built {} of {} files into {}",
+        files.len() - failures,
+        files.len(),
+        args.out_dir.display()
+    );
+    if failures > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+struct PageSettings<'a> {
+    parse_options: ParseOptions,
+    file_types: &'a FileTypes,
+    template: &'a str,
+    theme: &'a str,
+    options: &'a RenderOptions,
+}
+
+fn build_page(file: &Path, root: &Path, out_dir: &Path, page: &PageSettings) -> Result<(), String> {
+    let content = std::fs::read_to_string(file).map_err(|e| format!("read failed: {}", e))?;
+    let document = parse_file(file, &content, page.file_types, &page.parse_options)
+        .map_err(|e| format!("parse error: {}", e))?;
+
+    let relative = file.strip_prefix(root).unwrap_or(file);
+    let target = out_dir.join(relative).with_extension("html");
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("create dir failed: {}", e))?;
+    }
+
+    let fallback_title = relative.to_string_lossy();
+    let title = document.title().unwrap_or_else(|| fallback_title.to_string());
+    let document = expand_includes(file, document, page.file_types, &page.parse_options);
+    let html = renderer::render_page(page.template, &title, page.theme, &document.to_html_with(page.options));
+    std::fs::write(&target, html)
+        .map_err(|e| format!("write to {} failed: {}", target.display(), e))
+}
+
+// Plain text files become a preformatted block, everything else is markdown
+fn parse_file(file: &Path, content: &str, file_types: &FileTypes, options: &ParseOptions) -> Result<Document, String> {
+    match file_types.kind(file) {
+        Some(FileKind::Text) => Ok(Document::preformatted(content)),
+        _ => parser::parse_markdown(content, options),
+    }
+}
+
+// Included files are parsed like the file itself, wherever they are, as
+// long as they are markdown or text
+fn expand_includes(file: &Path, document: Document, file_types: &FileTypes, options: &ParseOptions) -> Document {
+    include::expand(document, file, |included| {
+        if !file_types.is_document(included) {
+            return Err("not a markdown or text file".to_string());
+        }
+        let content = std::fs::read_to_string(included).map_err(|e| e.to_string())?;
+        parse_file(included, &content, file_types, options)
+    })
+    .document
+}
+
+pub fn check(args: CheckArgs) -> ExitCode {
+    let (config, filter) = match load_config(&args.path, &args.filter.overrides()) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    let parse_options = parse_options(&config);
+    let file_types = config.watch.file_types();
+
+    let files = if args.path.is_file() {
+        vec![args.path.clone()]
+    } else {
+        match watcher::find_markdown_files(&args.path, &filter, &file_types) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Failed to scan {}: {}", args.path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+
+    let mut failures = 0;
+    for file in &files {
+        let result = std::fs::read_to_string(file)
+            .map_err(|e| format!("read failed: {}", e))
+            .and_then(|content| parse_file(file, &content, &file_types, &parse_options).map(|_| ()));
+        if let Err(e) = result {
+            eprintln!("{}: {}", file.display(), e);
+            failures += 1;
+        }
+    }
+
+    println!("Checked {} files, {} with errors", files.len(), failures);
+    if failures > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+// Best effort: a missing browser shouldn't take the server down
+pub fn open_browser(url: &str) {
+    let result = if cfg!(target_os = "macos") {
+        std::process::Command::new("open").arg(url).spawn()
+    } else if cfg!(target_os = "windows") {
+        std::process::Command::new("cmd")
+            .args(["/C", "start", url])
+            .spawn()
+    } else {
+        std::process::Command::new("xdg-open").arg(url).spawn()
+    };
+
+    if let Err(e) = result {
+        eprintln!("Failed to open browser: {}", e);
+    }
+}
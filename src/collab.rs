@@ -0,0 +1,575 @@
+// Collaborative editing sessions. The server holds the authoritative text of
+// every file being edited together with the operations applied to it; client
+// operations based on an older revision are transformed against the ones
+// they missed. Sessions are saved to disk shortly after each change.
+//
+// Events are broadcast while the session is locked, so every client sees the
+// operations of a session in the order they were applied.
+use crate::events::UpdateEvent;
+use crate::ot::{self, TextOperation};
+use crate::watcher::content_hash;
+use crate::websocket::ConnectionManager;
+use crate::workspace::Workspace;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Client id for changes that came from the file on disk
+pub const DISK_CLIENT_ID: u64 = 0;
+
+// How many operations a client may be behind before it has to rejoin
+const MAX_HISTORY: usize = 1000;
+
+// How long after a change the session is written to disk
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Peer {
+    pub client_id: u64,
+    pub name: String,
+    // selection in UTF-16 code units, anchor == head for a plain cursor
+    pub anchor: Option<usize>,
+    pub head: Option<usize>,
+}
+
+// What a client gets when joining: the text to start editing from
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub revision: u64,
+    pub content: String,
+    pub peers: Vec<Peer>,
+}
+
+struct Session {
+    text: String,
+    revision: u64,
+    // the last operations, history[i] turned revision history_start + i
+    // into the next one
+    history: Vec<TextOperation>,
+    history_start: u64,
+    peers: BTreeMap<u64, Peer>,
+    // hash of the content last read from or written to disk
+    disk_hash: u64,
+    save_pending: bool,
+}
+
+impl Session {
+    fn apply(&mut self, operation: TextOperation) -> Result<(), String> {
+        self.text = operation.apply(&self.text)?;
+        for peer in self.peers.values_mut() {
+            peer.anchor = peer.anchor.map(|index| operation.transform_index(index));
+            peer.head = peer.head.map(|index| operation.transform_index(index));
+        }
+        self.history.push(operation);
+        self.revision += 1;
+        if self.history.len() > MAX_HISTORY {
+            let excess = self.history.len() - MAX_HISTORY;
+            self.history.drain(..excess);
+            self.history_start += excess as u64;
+        }
+        Ok(())
+    }
+
+    // What a client sees: everything but its own cursor
+    fn snapshot(&self, client_id: u64) -> Snapshot {
+        Snapshot {
+            revision: self.revision,
+            content: self.text.clone(),
+            peers: self.peers.values().filter(|p| p.client_id != client_id).cloned().collect(),
+        }
+    }
+}
+
+// All sessions, keyed by relative path
+#[derive(Clone, Default)]
+pub struct CollabHub {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl CollabHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a client to the session for a file, starting one from the file on
+    // disk if nobody is editing it yet, and announces it to the others.
+    // Returns the text the client starts from.
+    pub async fn join(
+        &self,
+        workspace: &Workspace,
+        connection_manager: &ConnectionManager,
+        relative: &str,
+        client_id: u64,
+        name: String,
+    ) -> Result<Snapshot, String> {
+        // only served documents, as the session is written back to disk
+        workspace.resolve_document(relative)?;
+        let disk_text = if self.sessions.lock().unwrap().contains_key(relative) {
+            None
+        } else {
+            Some(workspace.read_file(relative).await?)
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(relative.to_string()).or_insert_with(|| {
+            let text = disk_text.unwrap_or_default();
+            Session {
+                disk_hash: content_hash(&text),
+                text,
+                revision: 0,
+                history: Vec::new(),
+                history_start: 0,
+                peers: BTreeMap::new(),
+                save_pending: false,
+            }
+        });
+        let peer = Peer {
+            client_id,
+            name,
+            anchor: None,
+            head: None,
+        };
+        session.peers.insert(client_id, peer.clone());
+
+        let snapshot = session.snapshot(client_id);
+        broadcast(
+            connection_manager,
+            UpdateEvent::PeerUpdate {
+                file_path: relative.to_string(),
+                peer,
+            },
+        );
+        Ok(snapshot)
+    }
+
+    // The current state of a session the client is in, for starting over
+    // after it missed operations
+    pub fn snapshot(&self, relative: &str, client_id: u64) -> Option<Snapshot> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(relative).filter(|session| session.peers.contains_key(&client_id))?;
+        Some(session.snapshot(client_id))
+    }
+
+    pub fn leave(&self, connection_manager: &ConnectionManager, relative: &str, client_id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(relative) else {
+            return;
+        };
+        let left = session.peers.remove(&client_id).is_some();
+        // the next editor starts over from the file; a pending save still
+        // needs the session and drops it when done
+        if session.peers.is_empty() && !session.save_pending {
+            sessions.remove(relative);
+        }
+        if left {
+            broadcast(
+                connection_manager,
+                UpdateEvent::PeerLeft {
+                    file_path: relative.to_string(),
+                    client_id,
+                },
+            );
+        }
+    }
+
+    // Applies a client's operation made against `revision`. The broadcast
+    // event acknowledges it to the sender and forwards it to the others.
+    pub fn edit(
+        &self,
+        connection_manager: &ConnectionManager,
+        relative: &str,
+        client_id: u64,
+        revision: u64,
+        operation: TextOperation,
+    ) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get_mut(relative)
+            .filter(|session| session.peers.contains_key(&client_id))
+            .ok_or_else(|| format!("Not editing {}", relative))?;
+        if revision > session.revision || revision < session.history_start {
+            return Err(format!(
+                "Revision {} of {} is unknown, rejoin to continue editing",
+                revision, relative
+            ));
+        }
+
+        let mut operation = operation;
+        let missed = (revision - session.history_start) as usize;
+        for concurrent in &session.history[missed..] {
+            operation = ot::transform(&operation, concurrent)?.0;
+        }
+        session.apply(operation.clone())?;
+
+        broadcast(
+            connection_manager,
+            UpdateEvent::CollabEdit {
+                file_path: relative.to_string(),
+                origin: client_id,
+                revision: session.revision,
+                operation,
+            },
+        );
+        Ok(())
+    }
+
+    // Moves a client's cursor or selection, given in the text at `revision`
+    pub fn select(
+        &self,
+        connection_manager: &ConnectionManager,
+        relative: &str,
+        client_id: u64,
+        revision: u64,
+        anchor: usize,
+        head: usize,
+    ) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(relative) else {
+            return;
+        };
+        if revision < session.history_start || revision > session.revision {
+            return;
+        }
+        let missed = (revision - session.history_start) as usize;
+        let (anchor, head) = session.history[missed..]
+            .iter()
+            .fold((anchor, head), |(anchor, head), operation| {
+                (operation.transform_index(anchor), operation.transform_index(head))
+            });
+        let Some(peer) = session.peers.get_mut(&client_id) else {
+            return;
+        };
+        peer.anchor = Some(anchor);
+        peer.head = Some(head);
+        broadcast(
+            connection_manager,
+            UpdateEvent::PeerUpdate {
+                file_path: relative.to_string(),
+                peer: peer.clone(),
+            },
+        );
+    }
+
+    // Called by the watcher with what is on disk now. Our own saves are
+    // recognized by their hash; anything else is merged into the session as
+    // an edit by the disk.
+    pub fn disk_changed(&self, connection_manager: &ConnectionManager, relative: &str, content: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(relative) else {
+            return;
+        };
+        let hash = content_hash(content);
+        if hash == session.disk_hash {
+            return;
+        }
+        session.disk_hash = hash;
+        if content == session.text {
+            return;
+        }
+
+        let operation = TextOperation::from_diff(&session.text, content);
+        if session.apply(operation.clone()).is_ok() {
+            println!("Merged the change on disk into the session for {}", relative);
+            broadcast(
+                connection_manager,
+                UpdateEvent::CollabEdit {
+                    file_path: relative.to_string(),
+                    origin: DISK_CLIENT_ID,
+                    revision: session.revision,
+                    operation,
+                },
+            );
+        }
+    }
+
+    // Writes the session to disk after SAVE_DELAY, batching the edits made
+    // in the meantime into one write
+    pub fn schedule_save(&self, workspace: &Workspace, relative: &str) {
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions.get_mut(relative) {
+                Some(session) if !session.save_pending => session.save_pending = true,
+                _ => return,
+            }
+        }
+
+        let hub = self.clone();
+        let workspace = workspace.clone();
+        let relative = relative.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(SAVE_DELAY).await;
+            let text = {
+                let mut sessions = hub.sessions.lock().unwrap();
+                // flush() may have saved it already
+                let Some(session) = sessions.get_mut(&relative).filter(|session| session.save_pending) else {
+                    return;
+                };
+                session.save_pending = false;
+                session.disk_hash = content_hash(&session.text);
+                session.text.clone()
+            };
+            if let Err(e) = write_atomically(&workspace, &relative, &text).await {
+                println!("Failed to save {}: {}", relative, e);
+            }
+            let mut sessions = hub.sessions.lock().unwrap();
+            if sessions
+                .get(&relative)
+                .is_some_and(|session| session.peers.is_empty() && !session.save_pending)
+            {
+                sessions.remove(&relative);
+            }
+        });
+    }
+
+    // Writes every session with unsaved edits now rather than after
+    // SAVE_DELAY, e.g. before the server exits
+    pub async fn flush(&self, workspace: &Workspace) {
+        let pending: Vec<(String, String)> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions
+                .iter_mut()
+                .filter(|(_, session)| session.save_pending)
+                .map(|(relative, session)| {
+                    session.save_pending = false;
+                    session.disk_hash = content_hash(&session.text);
+                    (relative.clone(), session.text.clone())
+                })
+                .collect()
+        };
+        for (relative, text) in pending {
+            if let Err(e) = write_atomically(workspace, &relative, &text).await {
+                println!("Failed to save {}: {}", relative, e);
+            }
+        }
+    }
+}
+
+fn broadcast(connection_manager: &ConnectionManager, event: UpdateEvent) {
+    // nobody listening is fine, the session is still up to date
+    let _ = connection_manager.broadcast(event);
+}
+
+// Writes next to the file and renames over it, so the watcher never reads a
+// half-written file and mistakes it for an edit
+async fn write_atomically(workspace: &Workspace, relative: &str, content: &str) -> Result<(), String> {
+    // checked again, the config may have changed since the session started
+    let path = workspace.resolve_writable(relative)?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("Invalid path: {}", relative))?
+        .to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.markdawn-save", file_name));
+    tokio::fs::write(&temp_path, content)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    tokio::fs::rename(&temp_path, &path)
+        .await
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::SequencedEvent;
+    use std::collections::VecDeque;
+    use tokio::sync::broadcast;
+
+    const PATH: &str = "notes.md";
+
+    // A client that behaves like static/collab.js: one operation in flight,
+    // anything typed meanwhile buffered and composed
+    struct Client {
+        id: u64,
+        text: String,
+        revision: u64,
+        outstanding: Option<TextOperation>,
+        buffer: Option<TextOperation>,
+        // (revision, operation) sent to the server and not delivered yet
+        outbox: VecDeque<(u64, TextOperation)>,
+        // CollabEdit events not delivered yet: (origin, revision, operation)
+        inbox: VecDeque<(u64, u64, TextOperation)>,
+    }
+
+    impl Client {
+        fn new(id: u64, text: &str) -> Self {
+            Self {
+                id,
+                text: text.to_string(),
+                revision: 0,
+                outstanding: None,
+                buffer: None,
+                outbox: VecDeque::new(),
+                inbox: VecDeque::new(),
+            }
+        }
+
+        fn type_text(&mut self, new_text: String) {
+            let operation = TextOperation::from_diff(&self.text, &new_text);
+            self.text = new_text;
+            if self.outstanding.is_none() {
+                self.send(operation);
+            } else {
+                let buffer = self.buffer.take();
+                self.buffer = Some(match buffer {
+                    Some(buffer) => ot::compose(&buffer, &operation).unwrap(),
+                    None => operation,
+                });
+            }
+        }
+
+        fn send(&mut self, operation: TextOperation) {
+            self.outstanding = Some(operation.clone());
+            self.outbox.push_back((self.revision, operation));
+        }
+
+        fn receive(&mut self) {
+            let Some((origin, revision, operation)) = self.inbox.pop_front() else {
+                return;
+            };
+            self.revision = revision;
+            if origin == self.id {
+                self.outstanding = None;
+                if let Some(buffer) = self.buffer.take() {
+                    self.send(buffer);
+                }
+                return;
+            }
+            let mut operation = operation;
+            if let Some(outstanding) = &self.outstanding {
+                let (outstanding, transformed) = ot::transform(outstanding, &operation).unwrap();
+                self.outstanding = Some(outstanding);
+                operation = transformed;
+            }
+            if let Some(buffer) = &self.buffer {
+                let (buffer, transformed) = ot::transform(buffer, &operation).unwrap();
+                self.buffer = Some(buffer);
+                operation = transformed;
+            }
+            self.text = operation.apply(&self.text).unwrap();
+        }
+    }
+
+    fn session(text: &str, clients: &[u64]) -> Session {
+        Session {
+            text: text.to_string(),
+            revision: 0,
+            history: Vec::new(),
+            history_start: 0,
+            peers: clients
+                .iter()
+                .map(|&client_id| {
+                    let peer = Peer {
+                        client_id,
+                        name: format!("Guest {}", client_id),
+                        anchor: None,
+                        head: None,
+                    };
+                    (client_id, peer)
+                })
+                .collect(),
+            disk_hash: content_hash(text),
+            save_pending: false,
+        }
+    }
+
+    // Random edits that stay on char boundaries
+    fn edit(seed: &mut u64, text: &str) -> String {
+        *seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let chars: Vec<char> = text.chars().collect();
+        let at = (*seed >> 33) as usize % (chars.len() + 1);
+        let mut chars = chars;
+        if (*seed >> 20).is_multiple_of(3) && at < chars.len() {
+            chars.remove(at);
+        } else {
+            chars.insert(at, ['x', 'y', 'é', '😀'][(*seed >> 40) as usize % 4]);
+        }
+        chars.into_iter().collect()
+    }
+
+    fn broadcast(events: &mut broadcast::Receiver<SequencedEvent>, clients: &mut [Client]) {
+        while let Ok(sequenced) = events.try_recv() {
+            if let UpdateEvent::CollabEdit {
+                origin,
+                revision,
+                operation,
+                ..
+            } = sequenced.event
+            {
+                for client in clients.iter_mut() {
+                    client
+                        .inbox
+                        .push_back((origin, revision, operation.clone()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn two_clients_converge() {
+        let hub = CollabHub::new();
+        let connection_manager = ConnectionManager::new();
+        let mut events = connection_manager.broadcast_tx.subscribe();
+        let initial = "hello world";
+        hub.sessions
+            .lock()
+            .unwrap()
+            .insert(PATH.to_string(), session(initial, &[1, 2]));
+        let mut clients = [Client::new(1, initial), Client::new(2, initial)];
+        let mut seed: u64 = 42;
+
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let client = &mut clients[(seed >> 60) as usize % 2];
+            match (seed >> 40) % 4 {
+                0 => {
+                    let new_text = edit(&mut seed, &client.text);
+                    client.type_text(new_text);
+                }
+                1 => {
+                    if let Some((revision, operation)) = client.outbox.pop_front() {
+                        hub.edit(&connection_manager, PATH, client.id, revision, operation)
+                            .unwrap();
+                    }
+                }
+                2 => client.receive(),
+                // now and then the file is changed on disk
+                _ if (seed >> 50).is_multiple_of(8) => {
+                    let text = hub.sessions.lock().unwrap()[PATH].text.clone();
+                    hub.disk_changed(&connection_manager, PATH, &edit(&mut seed, &text));
+                }
+                _ => {}
+            }
+            broadcast(&mut events, &mut clients);
+        }
+
+        // deliver everything still on its way
+        loop {
+            let mut busy = false;
+            for client in clients.iter_mut() {
+                if let Some((revision, operation)) = client.outbox.pop_front() {
+                    hub.edit(&connection_manager, PATH, client.id, revision, operation)
+                        .unwrap();
+                    busy = true;
+                }
+            }
+            broadcast(&mut events, &mut clients);
+            for client in clients.iter_mut() {
+                while !client.inbox.is_empty() {
+                    client.receive();
+                    busy = true;
+                }
+            }
+            if !busy {
+                break;
+            }
+        }
+
+        let server_text = hub.sessions.lock().unwrap()[PATH].text.clone();
+        assert_ne!(server_text, initial);
+        assert_eq!(clients[0].text, server_text);
+        assert_eq!(clients[1].text, server_text);
+    }
+}
@@ -0,0 +1,379 @@
+use crate::file_types::{self, FileTypes};
+use crate::filter::PathFilter;
+use crate::ignore::IgnoreRules;
+use crate::parser::ParseOptions;
+use crate::renderer::RenderOptions;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+pub const CONFIG_FILE_NAME: &str = "markdawn.toml";
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 5000;
+pub const DEFAULT_DEBOUNCE_MS: u64 = 300;
+pub const DEFAULT_MAX_WAIT_MS: u64 = 2000;
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_STATIC_DIR: &str = "static";
+pub const DEFAULT_HEARTBEAT_SECS: u64 = 15;
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 45;
+pub const DEFAULT_SEND_QUEUE: usize = 256;
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 1024;
+// version control metadata and dependency/build trees, which can hold far
+// more files than there are docs and rarely anything worth previewing
+pub const DEFAULT_EXCLUDES: [&str; 5] = ["**/.git", "**/.hg", "**/.svn", "**/node_modules", "**/target"];
+
+// Project-level settings read from markdawn.toml in the watched root.
+// Every section is optional; missing keys fall back to the defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub watch: WatchConfig,
+    pub markdown: MarkdownConfig,
+    pub ui: UiConfig,
+    pub render: RenderOptions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub static_dir: PathBuf,
+    // how often clients are pinged, and how long one may stay silent
+    // (not even answering pings) before it is disconnected
+    pub heartbeat_secs: u64,
+    pub idle_timeout_secs: u64,
+    // updates waiting for one slow client before they are dropped in favour
+    // of a resync
+    pub send_queue: usize,
+    // events the broadcast keeps for clients that haven't picked them up;
+    // read at startup
+    pub broadcast_capacity: usize,
+    // parses and renders of changed files running at once; 0 is one per CPU,
+    // at least two. Read at startup
+    pub render_workers: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            static_dir: PathBuf::from(DEFAULT_STATIC_DIR),
+            heartbeat_secs: DEFAULT_HEARTBEAT_SECS,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            send_queue: DEFAULT_SEND_QUEUE,
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            render_workers: 0,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn heartbeat(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    // a file is processed once it has had no events for debounce_ms, or
+    // max_wait_ms after its first unprocessed event if it keeps changing
+    pub debounce_ms: u64,
+    pub max_wait_ms: u64,
+    // how changes are noticed; polling works where native events don't
+    // arrive, e.g. on network filesystems
+    pub mode: WatchMode,
+    pub poll_interval_ms: u64,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // exclude DEFAULT_EXCLUDES as well
+    pub default_excludes: bool,
+    // honour .gitignore, .markdawnignore and .git/info/exclude
+    pub ignore_files: bool,
+    // which files are documents; see FileTypes
+    pub markdown_extensions: Vec<String>,
+    pub markdown_names: Vec<String>,
+    pub text_extensions: Vec<String>,
+    pub detect_markdown: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    // native events, falling back to polling if they can't be set up or
+    // the system's watch limit is reached
+    #[default]
+    Auto,
+    Native,
+    // compare modification times every poll_interval_ms
+    Poll,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+            max_wait_ms: DEFAULT_MAX_WAIT_MS,
+            mode: WatchMode::Auto,
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            default_excludes: true,
+            ignore_files: true,
+            markdown_extensions: strings(&file_types::DEFAULT_MARKDOWN_EXTENSIONS),
+            markdown_names: strings(&file_types::DEFAULT_MARKDOWN_NAMES),
+            text_extensions: strings(&file_types::DEFAULT_TEXT_EXTENSIONS),
+            detect_markdown: false,
+        }
+    }
+}
+
+impl WatchConfig {
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    // never shorter than the debounce itself, e.g. after --debounce-ms
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms.max(self.debounce_ms))
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn file_types(&self) -> FileTypes {
+        FileTypes {
+            markdown_extensions: self.markdown_extensions.clone(),
+            markdown_names: self.markdown_names.clone(),
+            text_extensions: self.text_extensions.clone(),
+            detect_content: self.detect_markdown,
+        }
+    }
+
+    // The include/exclude globs, without the ignore files
+    pub fn filter(&self) -> Result<PathFilter, String> {
+        let mut exclude = self.exclude.clone();
+        if self.default_excludes {
+            exclude.extend(strings(&DEFAULT_EXCLUDES));
+        }
+        PathFilter::new(&self.include, &exclude)
+    }
+
+    // The full filter for a root, with the ignore files currently under it
+    pub fn filter_for(&self, root: &Path) -> Result<PathFilter, String> {
+        let filter = self.filter()?;
+        if !self.ignore_files {
+            return Ok(filter);
+        }
+        let rules = IgnoreRules::load(root, |relative| filter.is_excluded(relative));
+        Ok(filter.with_ignore_rules(rules))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownConfig {
+    // names of the markdown extensions to enable; unset enables all of them,
+    // an empty list means strict CommonMark
+    pub extensions: Option<Vec<String>>,
+}
+
+impl MarkdownConfig {
+    pub fn parse_options(&self) -> Result<ParseOptions, String> {
+        match &self.extensions {
+            Some(names) => ParseOptions::with_extensions(names),
+            None => Ok(ParseOptions::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    // "light" or "dark"
+    pub theme: String,
+    // HTML page template with {{title}}, {{theme}} and {{content}} placeholders,
+    // relative to the config file
+    pub template: Option<PathBuf>,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            theme: "light".to_string(),
+            template: None,
+        }
+    }
+}
+
+// Values given on the command line; they always win over the file
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub static_dir: Option<PathBuf>,
+    pub debounce_ms: Option<u64>,
+    // --poll, with --poll-interval-ms
+    pub poll: bool,
+    pub poll_interval_ms: Option<u64>,
+    // appended to the file's include/exclude lists rather than replacing them
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // turns off the default excludes and ignore files
+    pub no_ignore: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut config: Config =
+            toml::from_str(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+
+        // resolve the template next to the config file, not the working directory
+        if let (Some(template), Some(dir)) = (&config.ui.template, path.parent()) {
+            config.ui.template = Some(dir.join(template));
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Loads markdawn.toml from root if present, defaults otherwise
+    pub fn discover(root: &Path) -> Result<Config, String> {
+        let path = config_path(root);
+        if path.is_file() {
+            Config::load(&path)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    pub fn apply(&mut self, overrides: &Overrides) {
+        if let Some(host) = &overrides.host {
+            self.server.host = host.clone();
+        }
+        if let Some(port) = overrides.port {
+            self.server.port = port;
+        }
+        if let Some(static_dir) = &overrides.static_dir {
+            self.server.static_dir = static_dir.clone();
+        }
+        if let Some(debounce_ms) = overrides.debounce_ms {
+            self.watch.debounce_ms = debounce_ms;
+        }
+        if overrides.poll {
+            self.watch.mode = WatchMode::Poll;
+        }
+        if let Some(poll_interval_ms) = overrides.poll_interval_ms {
+            self.watch.poll_interval_ms = poll_interval_ms;
+        }
+        self.watch.include.extend(overrides.include.iter().cloned());
+        self.watch.exclude.extend(overrides.exclude.iter().cloned());
+        if overrides.no_ignore {
+            self.watch.default_excludes = false;
+            self.watch.ignore_files = false;
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.watch.filter()?;
+        self.markdown.parse_options()?;
+        if self.server.heartbeat_secs == 0 || self.server.idle_timeout_secs < self.server.heartbeat_secs {
+            return Err("server.idle_timeout_secs must be at least server.heartbeat_secs, which can't be 0".to_string());
+        }
+        if self.watch.poll_interval_ms == 0 {
+            return Err("watch.poll_interval_ms must be at least 1".to_string());
+        }
+        if self.server.send_queue == 0 || self.server.broadcast_capacity == 0 {
+            return Err("server.send_queue and server.broadcast_capacity must be at least 1".to_string());
+        }
+        if !matches!(self.ui.theme.as_str(), "light" | "dark") {
+            return Err(format!("Unknown theme '{}', expected \"light\" or \"dark\"", self.ui.theme));
+        }
+        Ok(())
+    }
+
+    // Reads the page template, falling back to the built-in one
+    pub fn page_template(&self) -> Result<Option<String>, String> {
+        match &self.ui.template {
+            Some(path) => std::fs::read_to_string(path)
+                .map(Some)
+                .map_err(|e| format!("Failed to read template {}: {}", path.display(), e)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+// Config location for a watched path; single files use their directory
+pub fn config_path(root: &Path) -> PathBuf {
+    if root.is_file() {
+        root.parent().unwrap_or(Path::new(".")).join(CONFIG_FILE_NAME)
+    } else {
+        root.join(CONFIG_FILE_NAME)
+    }
+}
+
+// The effective configuration shared between the watcher and HTTP handlers.
+// Reloading re-reads the file and re-applies the command-line overrides.
+#[derive(Clone)]
+pub struct SharedConfig {
+    current: Arc<RwLock<Config>>,
+    path: PathBuf,
+    overrides: Arc<Overrides>,
+}
+
+impl SharedConfig {
+    pub fn load(root: &Path, overrides: Overrides) -> Result<Self, String> {
+        // absolute, so the watcher can recognise events for the config file
+        let root = root
+            .canonicalize()
+            .map_err(|e| format!("Failed to resolve {}: {}", root.display(), e))?;
+        let root = root.as_path();
+        let mut config = Config::discover(root)?;
+        config.apply(&overrides);
+        config.validate()?;
+
+        Ok(Self {
+            current: Arc::new(RwLock::new(config)),
+            path: config_path(root),
+            overrides: Arc::new(overrides),
+        })
+    }
+
+    pub fn get(&self) -> Config {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The command-line flags, for loading another root's config with them
+    pub fn overrides(&self) -> &Overrides {
+        &self.overrides
+    }
+
+    // On error the previous configuration stays in effect
+    pub fn reload(&self) -> Result<Config, String> {
+        let mut config = Config::discover(self.path.parent().unwrap_or(Path::new(".")))?;
+        config.apply(&self.overrides);
+        config.validate()?;
+
+        *self.current.write().unwrap() = config.clone();
+        Ok(config)
+    }
+}
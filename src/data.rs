@@ -1,27 +1,161 @@
-// An element is either plain, bold or italic
+// An inline element: plain or formatted text, code, math, a link or raw HTML
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Element {
     PlainText(String),
     Bold(String),
     Italic(String),
+    Code(String),
+    Strikethrough(String),
+    Math(String),
+    Link { text: String, url: String },
+    FootnoteRef(String),
+    Html(String),
 }
 
-// block-level element is either title, a paragraph, or a code block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Alignment {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListItem {
+    // Some for task list items: whether the box is ticked
+    pub checked: Option<bool>,
+    pub content: Vec<Element>,
+}
+
+// block-level element: title, paragraph, code block, list, table and friends
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Block {
     Title { level: u8, content: Vec<Element> },
     Paragraph(Vec<Element>),
-    CodeBlock(String),
+    CodeBlock { language: Option<String>, code: String },
+    // start is Some(n) for ordered lists numbered from n
+    List { start: Option<u64>, items: Vec<ListItem> },
+    Table {
+        alignments: Vec<Alignment>,
+        header: Vec<Vec<Element>>,
+        rows: Vec<Vec<Vec<Element>>>,
+    },
+    Math(String),
+    Html(String),
+    Footnote { label: String, content: Vec<Element> },
+    // `> ` lines, parsed as blocks of their own
+    BlockQuote(Vec<Block>),
+    // `{{include path}}`, the path as written; include::expand replaces it
+    // with the file's blocks
+    Include(String),
+    Rule,
 }
 
 // The whole file are blocks
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Document {
     pub blocks: Vec<Block>,
+    // raw text between the leading `---` fences, if any
+    pub front_matter: Option<String>,
+    // 1-based source line each block starts on, parallel to blocks. Empty for
+    // documents built by hand.
+    pub source_lines: Vec<usize>,
 }
 
 impl Document {
     pub fn new() -> Self {
-        Document { blocks: Vec::new() }
+        Document {
+            blocks: Vec::new(),
+            front_matter: None,
+            source_lines: Vec::new(),
+        }
+    }
+
+    // Plain text shown as is, in a single preformatted block
+    pub fn preformatted(text: &str) -> Self {
+        Document {
+            blocks: vec![Block::CodeBlock {
+                language: None,
+                code: text.to_string(),
+            }],
+            front_matter: None,
+            source_lines: vec![1],
+        }
+    }
+
+    // page title: `title:` from the front matter, else the first heading
+    pub fn title(&self) -> Option<String> {
+        self.front_matter_value("title").or_else(|| {
+            self.blocks.iter().find_map(|block| match block {
+                Block::Title { content, .. } => Some(plain_text(content)),
+                _ => None,
+            })
+        })
     }
+
+    // (level, plain text) of every heading, in document order
+    pub fn headings(&self) -> Vec<(u8, String)> {
+        self.blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Title { level, content } => Some((*level, plain_text(content))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Index of the block that source line `line` belongs to
+    pub fn block_at_line(&self, line: usize) -> Option<usize> {
+        block_at_line(&self.source_lines, line)
+    }
+
+    // Looks up a top-level `key: value` line in the front matter
+    pub fn front_matter_value(&self, key: &str) -> Option<String> {
+        self.front_matter.as_deref()?.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim() != key {
+                return None;
+            }
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            (!value.is_empty()).then(|| value.to_string())
+        })
+    }
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Index of the block containing `line`, given the start line of every block.
+// Lines before the first block belong to it.
+pub fn block_at_line(source_lines: &[usize], line: usize) -> Option<usize> {
+    match source_lines.partition_point(|&start| start <= line) {
+        0 => (!source_lines.is_empty()).then_some(0),
+        after => Some(after - 1),
+    }
+}
+
+// text content of inline elements with all formatting dropped
+pub fn plain_text(elements: &[Element]) -> String {
+    elements
+        .iter()
+        .map(|element| match element {
+            Element::PlainText(text)
+            | Element::Bold(text)
+            | Element::Italic(text)
+            | Element::Code(text)
+            | Element::Strikethrough(text)
+            | Element::Math(text)
+            | Element::FootnoteRef(text) => text.as_str(),
+            Element::Link { text, .. } => text.as_str(),
+            Element::Html(_) => "",
+        })
+        .collect()
 }
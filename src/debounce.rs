@@ -0,0 +1,150 @@
+// Per-path trailing-edge debouncing for file events. Editors often write a
+// file several times in a row (truncate, write, rename over, touch); a path
+// becomes due once it has been quiet for a while, so only the final content
+// is processed. A path that keeps changing is still let through every
+// `max_wait`, so the preview doesn't freeze during continuous writes.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub struct Debouncer {
+    quiet: Duration,
+    max_wait: Duration,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+struct Pending {
+    first_event: Instant,
+    due: Instant,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration, max_wait: Duration) -> Self {
+        Self {
+            quiet,
+            max_wait,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Applies to events from now on
+    pub fn set_durations(&mut self, quiet: Duration, max_wait: Duration) {
+        self.quiet = quiet;
+        self.max_wait = max_wait;
+    }
+
+    // Records an event, pushing the path's deadline back up to the cap
+    pub fn touch(&mut self, path: PathBuf) {
+        let now = Instant::now();
+        let pending = self.pending.entry(path).or_insert(Pending {
+            first_event: now,
+            due: now,
+        });
+        pending.due = (now + self.quiet).min(pending.first_event + self.max_wait);
+    }
+
+    // When the next path becomes due, None if nothing is pending
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.due).min()
+    }
+
+    // Removes and returns the paths that are due, oldest event first
+    pub fn take_due(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        self.take(|pending| pending.due <= now)
+    }
+
+    // Everything pending, due or not, e.g. when the watcher stops
+    pub fn take_all(&mut self) -> Vec<PathBuf> {
+        self.take(|_| true)
+    }
+
+    fn take(&mut self, wanted: impl Fn(&Pending) -> bool) -> Vec<PathBuf> {
+        let mut due: Vec<(Instant, PathBuf)> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| wanted(pending))
+            .map(|(path, pending)| (pending.first_event, path.clone()))
+            .collect();
+        due.sort();
+        for (_, path) in &due {
+            self.pending.remove(path);
+        }
+        due.into_iter().map(|(_, path)| path).collect()
+    }
+}
+
+// Sleeps until the deadline, or forever without one
+pub async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUIET: Duration = Duration::from_millis(100);
+    const MAX_WAIT: Duration = Duration::from_millis(150);
+
+    #[tokio::test]
+    async fn a_path_is_due_once_it_has_been_quiet() {
+        let mut debouncer = Debouncer::new(QUIET, MAX_WAIT);
+        assert_eq!(debouncer.next_due(), None);
+        let before = Instant::now();
+        debouncer.touch(PathBuf::from("a.md"));
+        let due = debouncer.next_due().unwrap();
+        assert!(due >= before + QUIET && due <= Instant::now() + QUIET);
+        assert!(debouncer.take_due().is_empty());
+
+        wait_until(Some(due)).await;
+        assert_eq!(debouncer.take_due(), [PathBuf::from("a.md")]);
+        assert_eq!(debouncer.next_due(), None);
+    }
+
+    #[tokio::test]
+    async fn events_push_the_deadline_back_up_to_the_max_wait() {
+        let mut debouncer = Debouncer::new(QUIET, MAX_WAIT);
+        debouncer.touch(PathBuf::from("a.md"));
+        let first_due = debouncer.next_due().unwrap();
+        let first_event = first_due - QUIET;
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        debouncer.touch(PathBuf::from("a.md"));
+        let second_due = debouncer.next_due().unwrap();
+        assert!(second_due > first_due);
+
+        // a quiet period from now would end after the cap
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        debouncer.touch(PathBuf::from("a.md"));
+        assert_eq!(debouncer.next_due(), Some(first_event + MAX_WAIT));
+    }
+
+    #[tokio::test]
+    async fn paths_are_taken_oldest_event_first() {
+        let mut debouncer = Debouncer::new(QUIET, MAX_WAIT);
+        debouncer.touch(PathBuf::from("b.md"));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        debouncer.touch(PathBuf::from("a.md"));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        // touching again doesn't make it newer
+        debouncer.touch(PathBuf::from("b.md"));
+        assert_eq!(debouncer.take_all(), [PathBuf::from("b.md"), PathBuf::from("a.md")]);
+        assert!(debouncer.take_all().is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_durations_apply_to_later_events() {
+        let mut debouncer = Debouncer::new(QUIET, MAX_WAIT);
+        debouncer.touch(PathBuf::from("a.md"));
+        let due = debouncer.next_due().unwrap();
+        debouncer.set_durations(Duration::ZERO, Duration::ZERO);
+        assert_eq!(debouncer.next_due(), Some(due));
+
+        debouncer.touch(PathBuf::from("b.md"));
+        assert_eq!(debouncer.take_due(), [PathBuf::from("b.md")]);
+    }
+}
@@ -1,29 +1,132 @@
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use crate::collab::Peer;
+use crate::ot::TextOperation;
+use crate::patch::BlockOp;
+use crate::presence::ClientInfo;
+use crate::protocol::ServerMessage;
+use crate::root::RootInfo;
 
+// file_path is always relative to the watched root, with `/` separators.
+// On the wire these become ServerMessage push events.
 #[derive(Debug, Clone)]
-pub enum FileEvent {
-    Created(PathBuf),
-    Modified(PathBuf),
-    Deleted(PathBuf),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UpdateEvent {
-    ContentUpdate { 
-        file_path: String, 
-        html: String 
+    // html has every block wrapped, see patch::blocks_to_html
+    ContentUpdate {
+        file_path: String,
+        html: String,
+        version: u64
+    },
+    // the ops turn version base_version of the file into version
+    ContentPatch {
+        file_path: String,
+        version: u64,
+        base_version: u64,
+        ops: Vec<BlockOp>
+    },
+    // a markdown file appeared; its content follows as a ContentUpdate
+    FileCreated {
+        file_path: String
+    },
+    FileDeleted {
+        file_path: String
+    },
+    // a markdown file was moved or renamed, content and all
+    FileRenamed {
+        old_path: String,
+        file_path: String
+    },
+    // a root was added or removed while the server runs; the file list
+    // changed with it
+    RootAdded {
+        root: RootInfo
+    },
+    RootRemoved {
+        name: String
+    },
+    // an editor's cursor is on `line`, which is inside block number `block`
+    ScrollTo {
+        file_path: String,
+        line: usize,
+        block: usize
+    },
+    // an operation applied to a collaborative session, turning it into
+    // `revision`; origin is the client that made it
+    CollabEdit {
+        file_path: String,
+        origin: u64,
+        revision: u64,
+        operation: TextOperation
+    },
+    // a collaborator joined or moved their cursor
+    PeerUpdate {
+        file_path: String,
+        peer: Peer
+    },
+    PeerLeft {
+        file_path: String,
+        client_id: u64
     },
-    FileDeleted { 
-        file_path: String 
+    // a client connected, or changed its name or the file it is viewing
+    ClientJoined {
+        client: ClientInfo
     },
-    Error { 
-        message: String 
+    ClientUpdated {
+        client: ClientInfo
     },
+    ClientLeft {
+        client_id: u64
+    },
+    Error {
+        // None for errors that aren't about a single file
+        file_path: Option<String>,
+        message: String
+    },
+}
+
+// An event as it goes through the broadcast, numbered in the order sent
+#[derive(Debug, Clone)]
+pub struct SequencedEvent {
+    pub id: u64,
+    pub event: UpdateEvent,
 }
 
 impl UpdateEvent {
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        serde_json::to_string(self)
+        ServerMessage::from(self.clone()).to_json()
+    }
+
+    // Events only for the clients editing the file
+    pub fn is_collab(&self) -> bool {
+        matches!(
+            self,
+            UpdateEvent::CollabEdit { .. } | UpdateEvent::PeerUpdate { .. } | UpdateEvent::PeerLeft { .. }
+        )
+    }
+
+    // Who is connected; not about any file, so every client gets these
+    pub fn is_presence(&self) -> bool {
+        matches!(
+            self,
+            UpdateEvent::ClientJoined { .. } | UpdateEvent::ClientUpdated { .. } | UpdateEvent::ClientLeft { .. }
+        )
     }
-}
\ No newline at end of file
+
+    pub fn file_path(&self) -> Option<&str> {
+        match self {
+            UpdateEvent::ContentUpdate { file_path, .. }
+            | UpdateEvent::ContentPatch { file_path, .. }
+            | UpdateEvent::FileCreated { file_path }
+            | UpdateEvent::FileDeleted { file_path }
+            | UpdateEvent::FileRenamed { file_path, .. }
+            | UpdateEvent::ScrollTo { file_path, .. }
+            | UpdateEvent::CollabEdit { file_path, .. }
+            | UpdateEvent::PeerUpdate { file_path, .. }
+            | UpdateEvent::PeerLeft { file_path, .. } => Some(file_path),
+            UpdateEvent::Error { file_path, .. } => file_path.as_deref(),
+            UpdateEvent::RootAdded { .. }
+            | UpdateEvent::RootRemoved { .. }
+            | UpdateEvent::ClientJoined { .. }
+            | UpdateEvent::ClientUpdated { .. }
+            | UpdateEvent::ClientLeft { .. } => None,
+        }
+    }
+}
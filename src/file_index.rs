@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+
+// What the file browser shows for one markdown file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub path: String,
+    // from the front matter or the first heading
+    pub title: Option<String>,
+    pub size: u64,
+    // seconds since the unix epoch
+    pub modified: u64,
+    // workspace::source_hash of the content, changes whenever it does
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileTreeNode {
+    Directory {
+        name: String,
+        path: String,
+        children: Vec<FileTreeNode>,
+    },
+    File {
+        name: String,
+        #[serde(flatten)]
+        entry: FileEntry,
+    },
+}
+
+// Markdown files under the watched root, keyed by relative path. Filled by
+// an initial scan and kept current by the watcher's create/modify/delete events.
+#[derive(Clone, Default)]
+pub struct FileIndex {
+    entries: Arc<RwLock<BTreeMap<String, FileEntry>>>,
+}
+
+impl FileIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Records a file; returns true if it wasn't known before
+    pub fn upsert(&self, path: &Path, relative: &str, title: Option<String>, hash: String) -> bool {
+        let (size, modified) = match std::fs::metadata(path) {
+            Ok(metadata) => (
+                metadata.len(),
+                metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0),
+            ),
+            Err(_) => (0, 0),
+        };
+
+        let entry = FileEntry {
+            path: relative.to_string(),
+            title,
+            size,
+            modified,
+            hash,
+        };
+        self.entries
+            .write()
+            .unwrap()
+            .insert(relative.to_string(), entry)
+            .is_none()
+    }
+
+    // Returns true if the file was known
+    pub fn remove(&self, relative: &str) -> bool {
+        self.entries.write().unwrap().remove(relative).is_some()
+    }
+
+    // Returns true if the file was known under its old path
+    pub fn rename(&self, from: &str, to: &str) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        let Some(mut entry) = entries.remove(from) else {
+            return false;
+        };
+        entry.path = to.to_string();
+        entries.insert(to.to_string(), entry);
+        true
+    }
+
+    pub fn get(&self, relative: &str) -> Option<FileEntry> {
+        self.entries.read().unwrap().get(relative).cloned()
+    }
+
+    pub fn contains(&self, relative: &str) -> bool {
+        self.entries.read().unwrap().contains_key(relative)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().unwrap().is_empty()
+    }
+
+    pub fn entries(&self) -> Vec<FileEntry> {
+        self.entries.read().unwrap().values().cloned().collect()
+    }
+
+    // Nests the flat, sorted entries into directories
+    pub fn tree(&self) -> Vec<FileTreeNode> {
+        let mut root = Vec::new();
+        for entry in self.entries() {
+            let segments: Vec<&str> = entry.path.split('/').collect();
+            insert_node(&mut root, &segments, 0, entry.clone());
+        }
+        root
+    }
+}
+
+fn insert_node(nodes: &mut Vec<FileTreeNode>, segments: &[&str], depth: usize, entry: FileEntry) {
+    let name = segments[depth].to_string();
+    if depth + 1 == segments.len() {
+        nodes.push(FileTreeNode::File { name, entry });
+        return;
+    }
+
+    let existing = nodes
+        .iter()
+        .position(|node| matches!(node, FileTreeNode::Directory { name: dir, .. } if *dir == name));
+    let index = match existing {
+        Some(index) => index,
+        None => {
+            nodes.push(FileTreeNode::Directory {
+                name,
+                path: segments[..=depth].join("/"),
+                children: Vec::new(),
+            });
+            nodes.len() - 1
+        }
+    };
+    if let FileTreeNode::Directory { children, .. } = &mut nodes[index] {
+        insert_node(children, segments, depth + 1, entry);
+    }
+}
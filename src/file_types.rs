@@ -0,0 +1,92 @@
+// Which files are documents, and how they are rendered: markdown by
+// extension or name, and plain text as preformatted text. Extensionless
+// files can also be recognised as markdown by their first lines.
+use std::io::Read;
+use std::path::Path;
+
+pub const DEFAULT_MARKDOWN_EXTENSIONS: [&str; 5] = ["md", "markdown", "mdown", "mkd", "mdx"];
+pub const DEFAULT_MARKDOWN_NAMES: [&str; 1] = ["README"];
+pub const DEFAULT_TEXT_EXTENSIONS: [&str; 1] = ["txt"];
+
+// Editors' lock and metadata files that still end in .md: Emacs' .#notes.md,
+// Office's ~$notes.md and macOS' ._notes.md. Swap and backup files such as
+// notes.md~, .notes.md.swp or notes.md___jb_tmp___ don't pass the extension
+// check in the first place.
+const TEMP_FILE_PREFIXES: [&str; 3] = [".#", "~$", "._"];
+
+// How much of an extensionless file is looked at to detect markdown
+const SNIFF_BYTES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Markdown,
+    // shown as preformatted text
+    Text,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileTypes {
+    // compared ignoring case, without the dot
+    pub markdown_extensions: Vec<String>,
+    // whole file names of extensionless markdown files, ignoring case
+    pub markdown_names: Vec<String>,
+    pub text_extensions: Vec<String>,
+    // treat other extensionless files as markdown if they look like it
+    pub detect_content: bool,
+}
+
+impl Default for FileTypes {
+    fn default() -> Self {
+        Self {
+            markdown_extensions: DEFAULT_MARKDOWN_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            markdown_names: DEFAULT_MARKDOWN_NAMES.iter().map(|name| name.to_string()).collect(),
+            text_extensions: DEFAULT_TEXT_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            detect_content: false,
+        }
+    }
+}
+
+impl FileTypes {
+    // None for files that aren't documents. Only reads the file when
+    // detecting content.
+    pub fn kind(&self, path: &Path) -> Option<FileKind> {
+        let name = path.file_name()?.to_str()?;
+        if TEMP_FILE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+            return None;
+        }
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if contains(&self.markdown_extensions, ext) => Some(FileKind::Markdown),
+            Some(ext) if contains(&self.text_extensions, ext) => Some(FileKind::Text),
+            Some(_) => None,
+            None if contains(&self.markdown_names, name) => Some(FileKind::Markdown),
+            None if self.detect_content && looks_like_markdown(path) => Some(FileKind::Markdown),
+            None => None,
+        }
+    }
+
+    pub fn is_document(&self, path: &Path) -> bool {
+        self.kind(path).is_some()
+    }
+}
+
+fn contains(list: &[String], value: &str) -> bool {
+    list.iter().any(|item| item.eq_ignore_ascii_case(value))
+}
+
+// Front matter or a heading on the first non-blank line
+fn looks_like_markdown(path: &Path) -> bool {
+    let mut buffer = Vec::new();
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    if file.take(SNIFF_BYTES as u64).read_to_end(&mut buffer).is_err() {
+        return false;
+    }
+    // the limit may cut a character in half
+    let text = String::from_utf8_lossy(&buffer);
+    match text.lines().find(|line| !line.trim().is_empty()) {
+        Some(line) => line.trim_end() == "---" || (line.starts_with('#') && line.trim_start_matches('#').starts_with(' ')),
+        None => false,
+    }
+}
@@ -0,0 +1,65 @@
+use crate::ignore::IgnoreRules;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+// Include/exclude globs and ignore files, matched against paths relative to
+// the watched root
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+    ignore: IgnoreRules,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
+            ignore: IgnoreRules::default(),
+        })
+    }
+
+    pub fn with_ignore_rules(self, ignore: IgnoreRules) -> Self {
+        Self { ignore, ..self }
+    }
+
+    // A path is excluded when it or any of its parent directories matches,
+    // so a plain `node_modules` pattern skips everything below it. Ignore
+    // files only exclude directories here, see matches for files.
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        self.matches_exclude(relative) || self.ignore.is_ignored(relative, true)
+    }
+
+    // Files must also match an include pattern when any are configured
+    pub fn matches(&self, relative: &Path) -> bool {
+        !self.matches_exclude(relative)
+            && !self.ignore.is_ignored(relative, false)
+            && (self.include.is_empty() || self.include.is_match(relative))
+    }
+
+    fn matches_exclude(&self, relative: &Path) -> bool {
+        relative
+            .ancestors()
+            .any(|p| !p.as_os_str().is_empty() && self.exclude.is_match(p))
+    }
+}
+
+impl Default for PathFilter {
+    fn default() -> Self {
+        Self {
+            include: GlobSet::empty(),
+            exclude: GlobSet::empty(),
+            ignore: IgnoreRules::default(),
+        }
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| format!("invalid glob '{}': {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
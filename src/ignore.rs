@@ -0,0 +1,224 @@
+// gitignore-style ignore files. A .gitignore or .markdawnignore applies to
+// the directory it is in and everything below it; rules in deeper files win
+// over shallower ones, later rules over earlier ones, and .markdawnignore over
+// .gitignore in the same directory. As with git, a file inside an ignored
+// directory can't be re-included by a negated rule.
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::{Path, PathBuf};
+
+// In order of precedence, lowest first
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".markdawnignore"];
+
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    // parents always come before their subdirectories
+    files: Vec<IgnoreFile>,
+}
+
+#[derive(Debug, Clone)]
+struct IgnoreFile {
+    // relative to the root, empty for the root itself
+    dir: PathBuf,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    matcher: GlobMatcher,
+    // `!pattern` re-includes what an earlier rule ignored
+    negated: bool,
+    // `pattern/` only matches directories
+    dir_only: bool,
+}
+
+impl IgnoreRules {
+    // Reads the ignore files under root, plus .git/info/exclude. Directories
+    // for which `skip` returns true, or that are ignored themselves, aren't
+    // searched.
+    pub fn load(root: &Path, skip: impl Fn(&Path) -> bool) -> Self {
+        let mut rules = Self::default();
+        rules.add_file(PathBuf::new(), &root.join(".git").join("info").join("exclude"));
+
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            for name in IGNORE_FILE_NAMES {
+                rules.add_file(dir.clone(), &root.join(&dir).join(name));
+            }
+            let Ok(entries) = std::fs::read_dir(root.join(&dir)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let relative = dir.join(entry.file_name());
+                if entry.path().is_dir() && !skip(&relative) && !rules.is_ignored(&relative, true) {
+                    pending.push(relative);
+                }
+            }
+        }
+        rules
+    }
+
+    fn add_file(&mut self, dir: PathBuf, path: &Path) {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return;
+        };
+        let rules: Vec<Rule> = text.lines().filter_map(parse_rule).collect();
+        if !rules.is_empty() {
+            self.files.push(IgnoreFile { dir, rules });
+        }
+    }
+
+    // Whether a path relative to the root is ignored, by its own rules or
+    // because a directory it is in is
+    pub fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+        relative
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| self.matches(dir, true))
+            || self.matches(relative, is_dir)
+    }
+
+    // The deciding rule is the last matching one in the deepest file
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        for file in self.files.iter().rev() {
+            let Ok(below) = relative.strip_prefix(&file.dir) else {
+                continue;
+            };
+            for rule in file.rules.iter().rev() {
+                if (is_dir || !rule.dir_only) && rule.matcher.is_match(below) {
+                    return !rule.negated;
+                }
+            }
+        }
+        false
+    }
+}
+
+// One line of an ignore file; None for blank lines, comments and patterns
+// that don't parse, which git skips as well
+fn parse_rule(line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (negated, pattern) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        // `\!` and `\#` start a pattern with a literal ! or #
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let dir_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    if pattern.is_empty() {
+        return None;
+    }
+
+    // a pattern with a slash is relative to the ignore file's directory,
+    // without one it matches a name at any depth
+    let glob = if pattern.contains('/') {
+        pattern.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{}", pattern)
+    };
+    let matcher = GlobBuilder::new(&glob)
+        .literal_separator(true)
+        .backslash_escape(true)
+        .build()
+        .ok()?
+        .compile_matcher();
+    Some(Rule {
+        matcher,
+        negated,
+        dir_only,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ignore files by directory, parents first, as load() finds them
+    fn rules(files: &[(&str, &str)]) -> IgnoreRules {
+        IgnoreRules {
+            files: files
+                .iter()
+                .map(|(dir, text)| IgnoreFile {
+                    dir: PathBuf::from(dir),
+                    rules: text.lines().filter_map(parse_rule).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn ignored(rules: &IgnoreRules, relative: &str) -> bool {
+        rules.is_ignored(Path::new(relative), false)
+    }
+
+    #[test]
+    fn names_match_at_any_depth_and_slashes_anchor() {
+        let rules = rules(&[("", "draft.md\n/notes/*.md\n# draft2.md\n\n\\#hash.md")]);
+        assert!(ignored(&rules, "draft.md"));
+        assert!(ignored(&rules, "a/b/draft.md"));
+        assert!(ignored(&rules, "notes/x.md"));
+        assert!(!ignored(&rules, "a/notes/x.md"));
+        // `*` doesn't cross directories
+        assert!(!ignored(&rules, "notes/deep/x.md"));
+        assert!(!ignored(&rules, "draft2.md"));
+        assert!(ignored(&rules, "#hash.md"));
+    }
+
+    #[test]
+    fn trailing_slashes_only_match_directories() {
+        let rules = rules(&[("", "build/")]);
+        assert!(rules.is_ignored(Path::new("build"), true));
+        assert!(!ignored(&rules, "build"));
+        assert!(ignored(&rules, "build/out.md"));
+        assert!(ignored(&rules, "docs/build/out.md"));
+    }
+
+    #[test]
+    fn later_and_deeper_rules_win() {
+        let rules = rules(&[("", "*.md\n!keep.md"), ("docs", "!*.md\nsecret.md")]);
+        assert!(ignored(&rules, "a.md"));
+        assert!(!ignored(&rules, "keep.md"));
+        assert!(!ignored(&rules, "docs/a.md"));
+        assert!(ignored(&rules, "docs/secret.md"));
+        // a file's rules only apply below its directory
+        assert!(ignored(&rules, "other/a.md"));
+    }
+
+    #[test]
+    fn files_in_ignored_directories_cannot_be_re_included() {
+        let rules = rules(&[("", "private/\n!private/readme.md")]);
+        assert!(ignored(&rules, "private/readme.md"));
+        assert!(ignored(&rules, "private/a.md"));
+    }
+
+    #[test]
+    fn load_reads_nested_files_and_git_excludes() {
+        let root = std::env::temp_dir().join(format!("markdawn-ignore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join(".git/info")).unwrap();
+        std::fs::create_dir_all(root.join("docs/skipped")).unwrap();
+        std::fs::create_dir_all(root.join("ignored")).unwrap();
+        std::fs::write(root.join(".git/info/exclude"), "local.md\n").unwrap();
+        std::fs::write(root.join(".gitignore"), "ignored/\n*.tmp.md\n").unwrap();
+        std::fs::write(root.join("docs/.gitignore"), "a.md\nb.md\n").unwrap();
+        // wins over .gitignore in the same directory
+        std::fs::write(root.join("docs/.markdawnignore"), "!b.md\n").unwrap();
+        // never read: its directory is skipped, or ignored itself
+        std::fs::write(root.join("docs/skipped/.gitignore"), "c.md\n").unwrap();
+        std::fs::write(root.join("ignored/.markdawnignore"), "!*\n").unwrap();
+
+        let rules = IgnoreRules::load(&root, |relative| relative == Path::new("docs/skipped"));
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(ignored(&rules, "local.md"));
+        assert!(ignored(&rules, "x.tmp.md"));
+        assert!(ignored(&rules, "docs/a.md"));
+        assert!(!ignored(&rules, "docs/b.md"));
+        assert!(!ignored(&rules, "docs/skipped/c.md"));
+        assert!(ignored(&rules, "ignored/x.md"));
+        assert_eq!(rules.files.len(), 4);
+    }
+}
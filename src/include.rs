@@ -0,0 +1,236 @@
+// Transclusion: a `{{include chapter.md}}` block is replaced by that file's
+// blocks, which may include further files. Paths are relative to the file
+// doing the including. A file that ends up including itself, directly or
+// through others, gets an error block there instead of recursing.
+use crate::data::{Block, Document};
+use crate::renderer::escape_html;
+use std::path::{Component, Path, PathBuf};
+
+// includes inside includes, as a guard against chains through symlinks
+// that the cycle check can't see
+const MAX_DEPTH: usize = 16;
+
+pub struct Expanded {
+    pub document: Document,
+    // every file an include pointed at, found or not, so the expansion can
+    // be redone when one of them changes
+    pub dependencies: Vec<PathBuf>,
+}
+
+// `load` reads and parses an included file, or says why it can't be included
+pub fn expand<F>(document: Document, path: &Path, mut load: F) -> Expanded
+where
+    F: FnMut(&Path) -> Result<Document, String>,
+{
+    let mut dependencies = Vec::new();
+    let mut chain = vec![normalize(path)];
+    let document = expand_into(document, &mut chain, &mut dependencies, &mut load);
+    Expanded { document, dependencies }
+}
+
+fn expand_into<F>(document: Document, chain: &mut Vec<PathBuf>, dependencies: &mut Vec<PathBuf>, load: &mut F) -> Document
+where
+    F: FnMut(&Path) -> Result<Document, String>,
+{
+    if !document.blocks.iter().any(|block| matches!(block, Block::Include(_))) {
+        return document;
+    }
+    let dir = chain
+        .last()
+        .and_then(|path| path.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let has_lines = document.source_lines.len() == document.blocks.len();
+    let mut blocks = Vec::new();
+    let mut source_lines = Vec::new();
+
+    for (index, block) in document.blocks.into_iter().enumerate() {
+        let line = document.source_lines.get(index).copied().unwrap_or_default();
+        let Block::Include(target) = block else {
+            blocks.push(block);
+            source_lines.push(line);
+            continue;
+        };
+        let included = normalize(&dir.join(&target));
+        if !dependencies.contains(&included) {
+            dependencies.push(included.clone());
+        }
+
+        let result = if chain.contains(&included) {
+            Err("it includes this file".to_string())
+        } else if chain.len() > MAX_DEPTH {
+            Err("includes are nested too deeply".to_string())
+        } else {
+            load(&included)
+        };
+        match result {
+            Ok(document) => {
+                chain.push(included);
+                let document = expand_into(document, chain, dependencies, load);
+                chain.pop();
+                // included blocks point back at the include line
+                source_lines.extend(std::iter::repeat_n(line, document.blocks.len()));
+                blocks.extend(document.blocks);
+            }
+            Err(e) => {
+                blocks.push(Block::Html(format!(
+                    "<div class=\"error include-error\">Cannot include {}: {}</div>",
+                    escape_html(&target),
+                    escape_html(&e)
+                )));
+                source_lines.push(line);
+            }
+        }
+    }
+
+    Document {
+        blocks,
+        front_matter: document.front_matter,
+        source_lines: if has_lines { source_lines } else { Vec::new() },
+    }
+}
+
+// `a/./b/../c.md` -> `a/c.md`, without touching the file system, as
+// included files may not exist (yet)
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // A document with a line per block: `{{x.md}}` includes x.md, anything
+    // else is a code block with that text
+    fn document(lines: &[&str]) -> Document {
+        let blocks = lines
+            .iter()
+            .map(|line| match line.strip_prefix("{{").and_then(|line| line.strip_suffix("}}")) {
+                Some(target) => Block::Include(target.to_string()),
+                None => Block::CodeBlock {
+                    language: None,
+                    code: line.to_string(),
+                },
+            })
+            .collect();
+        Document {
+            blocks,
+            front_matter: None,
+            source_lines: (1..=lines.len()).collect(),
+        }
+    }
+
+    fn expand_files(files: &[(&str, &[&str])], path: &str) -> Expanded {
+        let files: HashMap<PathBuf, Document> = files
+            .iter()
+            .map(|(path, lines)| (PathBuf::from(path), document(lines)))
+            .collect();
+        let path = Path::new(path);
+        expand(files[path].clone(), path, |included| {
+            files.get(included).cloned().ok_or_else(|| "No such file".to_string())
+        })
+    }
+
+    fn texts(document: &Document) -> Vec<String> {
+        document
+            .blocks
+            .iter()
+            .map(|block| match block {
+                Block::CodeBlock { code, .. } => code.clone(),
+                Block::Html(html) => html.clone(),
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let expanded = expand_files(
+            &[
+                ("/r/index.md", &["top", "{{parts/a.md}}", "bottom"]),
+                ("/r/parts/a.md", &["a", "{{../b.md}}"]),
+                ("/r/b.md", &["b"]),
+            ],
+            "/r/index.md",
+        );
+        assert_eq!(texts(&expanded.document), ["top", "a", "b", "bottom"]);
+        // included blocks point back at the include line
+        assert_eq!(expanded.document.source_lines, [1, 2, 2, 3]);
+        assert_eq!(
+            expanded.dependencies,
+            [PathBuf::from("/r/parts/a.md"), PathBuf::from("/r/b.md")]
+        );
+    }
+
+    #[test]
+    fn a_file_including_itself_gets_an_error_block() {
+        let expanded = expand_files(&[("/r/a.md", &["a", "{{./a.md}}"])], "/r/a.md");
+        let texts = texts(&expanded.document);
+        assert_eq!(texts[0], "a");
+        assert!(texts[1].contains("Cannot include ./a.md: it includes this file"));
+    }
+
+    #[test]
+    fn cycles_through_other_files_stop_where_they_close() {
+        let expanded = expand_files(
+            &[
+                ("/r/a.md", &["a", "{{b.md}}"]),
+                ("/r/b.md", &["b", "{{c.md}}"]),
+                ("/r/c.md", &["c", "{{a.md}}"]),
+            ],
+            "/r/a.md",
+        );
+        let texts = texts(&expanded.document);
+        assert_eq!(texts[..3], ["a", "b", "c"]);
+        assert!(texts[3].contains("Cannot include a.md: it includes this file"));
+        assert_eq!(texts.len(), 4);
+        // a change to any of them re-renders a
+        assert_eq!(
+            expanded.dependencies,
+            [PathBuf::from("/r/b.md"), PathBuf::from("/r/c.md"), PathBuf::from("/r/a.md")]
+        );
+    }
+
+    #[test]
+    fn the_same_file_can_be_included_twice_without_a_cycle() {
+        let expanded = expand_files(
+            &[("/r/a.md", &["{{b.md}}", "{{b.md}}"]), ("/r/b.md", &["b"])],
+            "/r/a.md",
+        );
+        assert_eq!(texts(&expanded.document), ["b", "b"]);
+        assert_eq!(expanded.dependencies, [PathBuf::from("/r/b.md")]);
+    }
+
+    #[test]
+    fn missing_files_are_errors_and_still_dependencies() {
+        let expanded = expand_files(&[("/r/a.md", &["{{<missing>.md}}"])], "/r/a.md");
+        let texts = texts(&expanded.document);
+        assert!(texts[0].contains("Cannot include &lt;missing&gt;.md: No such file"));
+        assert_eq!(expanded.dependencies, [PathBuf::from("/r/<missing>.md")]);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        // 0.md includes 1.md, which includes 2.md, and so on without end
+        let expanded = expand(document(&["{{1.md}}"]), Path::new("/r/0.md"), |included| {
+            let depth: usize = included.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            Ok(document(&[&format!("{{{{{}.md}}}}", depth + 1)]))
+        });
+        let texts = texts(&expanded.document);
+        assert_eq!(texts.len(), 1);
+        let error = format!("Cannot include {}.md: includes are nested too deeply", MAX_DEPTH + 1);
+        assert!(texts[0].contains(&error));
+        assert_eq!(expanded.dependencies.len(), MAX_DEPTH + 1);
+    }
+}
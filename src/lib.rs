@@ -0,0 +1,73 @@
+// markdawn: a markdown parser and renderer, plus the live-preview server
+// built on top of it. Only the parser and renderer are always available;
+// the watcher, WebSocket broadcaster and HTTP server need the `server` feature.
+
+pub mod data;
+pub mod include;
+pub mod parser;
+pub mod renderer;
+
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "config")]
+pub mod file_types;
+#[cfg(feature = "config")]
+pub mod filter;
+#[cfg(feature = "config")]
+pub mod ignore;
+
+#[cfg(feature = "server")]
+pub mod collab;
+#[cfg(feature = "server")]
+pub mod debounce;
+#[cfg(feature = "server")]
+pub mod events;
+#[cfg(feature = "server")]
+pub mod file_index;
+#[cfg(feature = "server")]
+pub mod ot;
+#[cfg(feature = "server")]
+pub mod outbox;
+#[cfg(feature = "server")]
+pub mod patch;
+#[cfg(feature = "server")]
+pub mod presence;
+#[cfg(feature = "server")]
+pub mod protocol;
+#[cfg(feature = "server")]
+pub mod render_pool;
+#[cfg(feature = "server")]
+pub mod root;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod sse;
+#[cfg(feature = "server")]
+pub mod watcher;
+#[cfg(feature = "server")]
+pub mod websocket;
+#[cfg(feature = "server")]
+pub mod workspace;
+
+pub use data::{Block, Document, Element};
+pub use parser::ParseOptions;
+pub use renderer::RenderOptions;
+
+#[cfg(feature = "server")]
+pub use events::UpdateEvent;
+#[cfg(feature = "server")]
+pub use watcher::FileWatcher;
+#[cfg(feature = "server")]
+pub use websocket::ConnectionManager;
+#[cfg(feature = "server")]
+pub use workspace::Workspace;
+
+// Parses markdown text into a document tree with every extension enabled
+pub fn parse(text: &str) -> Result<Document, String> {
+    parser::parse_markdown(text, &ParseOptions::default())
+}
+
+// Parses markdown text with a chosen set of extensions
+pub fn parse_with(text: &str, options: &ParseOptions) -> Result<Document, String> {
+    parser::parse_markdown(text, options)
+}
@@ -1,118 +1,153 @@
-mod data;
-mod events;
-mod parser;
-mod renderer;
-mod watcher;
-mod websocket;
-
-use axum::{
-    Json, Router,
-    response::Html,
-    routing::{get, post},
-};
-use websocket::ConnectionManager;
-use watcher::FileWatcher;
-use data::{Block, Document, Element};
-use serde::{Deserialize, Serialize};
-use tower_http::services::ServeDir;
-
-#[derive(Deserialize)]
-struct MarkdownRequest {
-    content: String,
-}
+mod cli;
+
+use clap::Parser;
+use cli::{Cli, Command, ServeArgs};
+use markdawn::config::SharedConfig;
+use markdawn::root::{self, Root};
+use markdawn::server::{self, AppState};
+use markdawn::watcher::Watchers;
+use markdawn::{ConnectionManager, Workspace};
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::ExitCode;
 
-#[derive(Serialize)]
-struct MarkdownResponse {
-    html: String,
-    success: bool,
-    message: String,
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or_else(|| Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime.block_on(serve(args)),
+            Err(e) => {
+                eprintln!("Failed to start async runtime: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        Command::Render(args) => cli::render(args),
+        Command::Build(args) => cli::build(args),
+        Command::Check(args) => cli::check(args),
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    // Initialize the connection manager for WebSocket handling
-    let connection_manager = ConnectionManager::new();
-
-    // Initialize file watcher
-    let mut file_watcher = FileWatcher::new(connection_manager.clone());
-    
-    // Start watching for file changes in a background task
-    tokio::spawn(async move {
-        // Watch current directory for .md files
-        if let Err(e) = file_watcher.start_watching(".").await {
-            eprintln!("Failed to start file watcher: {}", e);
+async fn serve(args: ServeArgs) -> ExitCode {
+    let roots = match open_roots(&args) {
+        Ok(roots) => roots,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
         }
-    });
-
-    let app = Router::new()
-        .route("/", get(serve_index))
-        .route("/demo", get(serve_demo))
-        .route("/api/convert", post(convert_markdown))
-        // WebSocket endpoint for real-time updates
-        .route("/ws", get(websocket::websocket_handler))
-        // Pass connection_manager as application state
-        .with_state(connection_manager.clone())
-        .nest_service("/static", ServeDir::new("static"));
-
-    let listener = match tokio::net::TcpListener::bind("127.0.0.1:5000").await {
+    };
+
+    // the server settings come from the first root's markdawn.toml
+    let config = roots[0].config.get();
+    let workspace = Workspace::new(roots[0].config.clone());
+    for root in &roots {
+        if let Err(e) = workspace.add_root(root.clone()) {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    workspace.scan();
+
+    // Initialize the connection manager for WebSocket handling
+    let connection_manager = ConnectionManager::with_capacity(config.server.broadcast_capacity);
+
+    // one watcher per root, each in a background task
+    let watchers = Watchers::new(connection_manager.clone(), workspace.clone(), !args.no_watch);
+    for root in &roots {
+        watchers.start(root.clone());
+    }
+
+    let state = AppState {
+        connection_manager: connection_manager.clone(),
+        workspace,
+        watchers,
+    };
+
+    // the bind address is only read at startup; changing it needs a restart
+    let address = format!("{}:{}", config.server.host, config.server.port);
+    let listener = match tokio::net::TcpListener::bind(&address).await {
         Ok(listener) => listener,
         Err(e) => {
-            eprintln!("Failed to bind to port 5000: {}", e);
+            eprintln!("Failed to bind to {}: {}", address, e);
             eprintln!("Maybe another instance is running? Try: pkill -f markdawn");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    println!("Server Running on http://localhost:5000");
-    println!("Open your browser and edit test.md or README.md to see real-time updates!");
+    let url = format!("http://{}", address);
+    println!("Server Running on {}", url);
+    if args.no_watch {
+        println!("File watching disabled (--no-watch)");
+    } else {
+        let paths: Vec<String> = roots.iter().map(|root| root.info().path).collect();
+        println!("Watching {} - edit a markdown file to see real-time updates!", paths.join(", "));
+    }
+    if args.open {
+        cli::open_browser(&url);
+    }
 
-    if let Err(e) = axum::serve(listener, app).await {
+    if let Err(e) = server::serve(listener, state, shutdown_signal()).await {
         eprintln!("Server error: {}", e);
+        return ExitCode::FAILURE;
     }
+    println!("Server stopped");
+    ExitCode::SUCCESS
 }
 
-async fn serve_index() -> Html<&'static str> {
-    Html(include_str!("../static/index.html"))
-}
+// Ctrl-C, or SIGTERM from a service manager
+async fn shutdown_signal() {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-async fn serve_demo() -> Html<String> {
-    let sample_doc = create_sample_document();
-    Html(sample_doc.to_html())
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
 
-fn create_sample_document() -> Document {
-    use data::*;
-    Document {
-        blocks: vec![
-            Block::Title {
-                level: 1,
-                content: vec![Element::PlainText("Sample Document".to_string())],
-            },
-            Block::Paragraph(vec![
-                Element::PlainText("This is a ".to_string()),
-                Element::Bold("bold".to_string()),
-                Element::PlainText(" and ".to_string()),
-                Element::Italic("italic".to_string()),
-                Element::PlainText(" text example.".to_string()),
-            ]),
-        ],
+// Each path with markdawn.toml from its directory and the command-line flags
+// on top. A single path is served at the top level; several each get their
+// directory's or file's name, made unique with a number.
+fn open_roots(args: &ServeArgs) -> Result<Vec<Root>, String> {
+    let mut names = HashSet::new();
+    let mut roots = Vec::new();
+    for path in &args.paths {
+        if !path.exists() {
+            return Err(format!("Path does not exist: {}", path.display()));
+        }
+        let name = if args.paths.len() == 1 {
+            String::new()
+        } else {
+            unique_name(&mut names, path)
+        };
+        let config = SharedConfig::load(path, args.overrides())?;
+        roots.push(Root::new(path, &name, config)?);
     }
+    Ok(roots)
 }
 
-async fn convert_markdown(Json(payload): Json<MarkdownRequest>) -> Json<MarkdownResponse> {
-    match parser::parse_markdown(&payload.content) {
-        Ok(document) => {
-            let html = document.to_html();
-            Json(MarkdownResponse {
-                html,
-                success: true,
-                message: "Conversion successful".to_string(),
-            })
-        }
-        Err(e) => Json(MarkdownResponse {
-            html: String::new(),
-            success: false,
-            message: format!("Parse error: {}", e),
-        }),
+fn unique_name(names: &mut HashSet<String>, path: &Path) -> String {
+    let base = root::default_name(path);
+    let mut name = base.clone();
+    let mut number = 2;
+    while !names.insert(name.clone()) {
+        name = format!("{}-{}", base, number);
+        number += 1;
     }
-}
\ No newline at end of file
+    name
+}
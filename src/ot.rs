@@ -0,0 +1,505 @@
+// Operational transform for plain text, wire compatible with ot.js: an
+// operation is a list where a positive number retains that many characters,
+// a negative number deletes that many and a string is inserted. Lengths count
+// UTF-16 code units, like JavaScript strings and textarea selections do.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Component {
+    Retain(usize),
+    // the text and its length in UTF-16 code units
+    Insert(String, usize),
+    Delete(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "Vec<RawComponent>", into = "Vec<RawComponent>")]
+pub struct TextOperation {
+    components: Vec<Component>,
+    // length of the text the operation applies to
+    base_len: usize,
+    // length of the text it produces
+    target_len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawComponent {
+    Count(i64),
+    Insert(String),
+}
+
+impl TryFrom<Vec<RawComponent>> for TextOperation {
+    type Error = String;
+
+    // Counts come from clients: lengths that don't fit are refused rather
+    // than overflowing
+    fn try_from(raw: Vec<RawComponent>) -> Result<Self, String> {
+        let too_long = || "Operation is too long".to_string();
+        let mut operation = TextOperation::new();
+        for component in raw {
+            match component {
+                RawComponent::Count(0) => return Err("Empty retain or delete in operation".to_string()),
+                RawComponent::Count(n) => {
+                    let len = usize::try_from(n.unsigned_abs()).map_err(|_| too_long())?;
+                    operation.base_len.checked_add(len).ok_or_else(too_long)?;
+                    if n > 0 {
+                        operation.target_len.checked_add(len).ok_or_else(too_long)?;
+                        operation.retain(len);
+                    } else {
+                        operation.delete(len);
+                    }
+                }
+                RawComponent::Insert(text) => {
+                    operation.target_len.checked_add(text.len()).ok_or_else(too_long)?;
+                    operation.insert(&text);
+                }
+            }
+        }
+        Ok(operation)
+    }
+}
+
+impl From<TextOperation> for Vec<RawComponent> {
+    fn from(operation: TextOperation) -> Self {
+        operation
+            .components
+            .into_iter()
+            .map(|component| match component {
+                Component::Retain(n) => RawComponent::Count(n as i64),
+                Component::Insert(text, _) => RawComponent::Insert(text),
+                Component::Delete(n) => RawComponent::Count(-(n as i64)),
+            })
+            .collect()
+    }
+}
+
+impl TextOperation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn base_len(&self) -> usize {
+        self.base_len
+    }
+
+    pub fn target_len(&self) -> usize {
+        self.target_len
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.components.iter().all(|c| matches!(c, Component::Retain(_)))
+    }
+
+    pub fn retain(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        self.base_len += n;
+        self.target_len += n;
+        if let Some(Component::Retain(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Retain(n));
+        }
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let len = text.encode_utf16().count();
+        self.target_len += len;
+        // inserts go before deletes at the same position, so equal
+        // operations always have the same components
+        let count = self.components.len();
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last, last_len)] => {
+                last.push_str(text);
+                *last_len += len;
+            }
+            [.., Component::Insert(before, before_len), Component::Delete(_)] => {
+                before.push_str(text);
+                *before_len += len;
+            }
+            [.., Component::Delete(_)] => {
+                self.components.insert(count - 1, Component::Insert(text.to_string(), len));
+            }
+            _ => self.components.push(Component::Insert(text.to_string(), len)),
+        }
+    }
+
+    pub fn delete(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        self.base_len += n;
+        if let Some(Component::Delete(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Delete(n));
+        }
+    }
+
+    // The operation turning `old` into `new`: one edit between their common
+    // prefix and suffix
+    pub fn from_diff(old: &str, new: &str) -> Self {
+        let old_units: Vec<u16> = old.encode_utf16().collect();
+        let new_units: Vec<u16> = new.encode_utf16().collect();
+        let mut prefix = old_units.iter().zip(&new_units).take_while(|(a, b)| a == b).count();
+        // don't split a surrogate pair
+        if prefix > 0 && (0xD800..0xDC00).contains(&old_units[prefix - 1]) {
+            prefix -= 1;
+        }
+        let mut suffix = old_units[prefix..]
+            .iter()
+            .rev()
+            .zip(new_units[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if suffix > 0 && (0xDC00..0xE000).contains(&old_units[old_units.len() - suffix]) {
+            suffix -= 1;
+        }
+
+        let mut operation = TextOperation::new();
+        operation.retain(prefix);
+        operation.delete(old_units.len() - prefix - suffix);
+        operation.insert(&String::from_utf16_lossy(&new_units[prefix..new_units.len() - suffix]));
+        operation.retain(suffix);
+        operation
+    }
+
+    pub fn apply(&self, text: &str) -> Result<String, String> {
+        let units: Vec<u16> = text.encode_utf16().collect();
+        if units.len() != self.base_len {
+            return Err(format!(
+                "Operation expects a text of length {}, got {}",
+                self.base_len,
+                units.len()
+            ));
+        }
+        let mut result: Vec<u16> = Vec::with_capacity(self.target_len);
+        let mut index = 0;
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => {
+                    result.extend_from_slice(&units[index..index + n]);
+                    index += n;
+                }
+                Component::Insert(text, _) => result.extend(text.encode_utf16()),
+                Component::Delete(n) => index += n,
+            }
+        }
+        String::from_utf16(&result).map_err(|_| "Operation splits a character".to_string())
+    }
+
+    // Where a position in the base text ends up after this operation
+    pub fn transform_index(&self, index: usize) -> usize {
+        let mut position = 0;
+        let mut new_index = index;
+        for component in &self.components {
+            if position > index {
+                break;
+            }
+            match component {
+                Component::Retain(n) => position += n,
+                // indexes come from clients and may be anything
+                Component::Insert(_, len) => new_index = new_index.saturating_add(*len),
+                Component::Delete(n) => {
+                    new_index -= (*n).min(index - position);
+                    position += n;
+                }
+            }
+        }
+        new_index
+    }
+}
+
+// Given concurrent operations a and b on the same text, returns (a', b') such
+// that applying a then b' gives the same text as b then a'. When both insert
+// at the same place, a's text goes first.
+pub fn transform(a: &TextOperation, b: &TextOperation) -> Result<(TextOperation, TextOperation), String> {
+    if a.base_len != b.base_len {
+        return Err("Concurrent operations must apply to the same text".to_string());
+    }
+
+    let mut a_prime = TextOperation::new();
+    let mut b_prime = TextOperation::new();
+    let mut a_components = a.components.iter().cloned();
+    let mut b_components = b.components.iter().cloned();
+    let mut a_current = a_components.next();
+    let mut b_current = b_components.next();
+
+    loop {
+        match (&mut a_current, &mut b_current) {
+            (None, None) => break,
+            (Some(Component::Insert(text, len)), _) => {
+                a_prime.insert(text);
+                b_prime.retain(*len);
+                a_current = a_components.next();
+            }
+            (_, Some(Component::Insert(text, len))) => {
+                a_prime.retain(*len);
+                b_prime.insert(text);
+                b_current = b_components.next();
+            }
+            (Some(a_component), Some(b_component)) => {
+                let a_len = component_len(a_component);
+                let b_len = component_len(b_component);
+                let n = a_len.min(b_len);
+                match (&*a_component, &*b_component) {
+                    (Component::Retain(_), Component::Retain(_)) => {
+                        a_prime.retain(n);
+                        b_prime.retain(n);
+                    }
+                    // both deleted it, nothing left to do
+                    (Component::Delete(_), Component::Delete(_)) => {}
+                    (Component::Delete(_), Component::Retain(_)) => a_prime.delete(n),
+                    (Component::Retain(_), Component::Delete(_)) => b_prime.delete(n),
+                    _ => unreachable!("inserts are handled above"),
+                }
+                a_current = shorten(a_component, n).or_else(|| a_components.next());
+                b_current = shorten(b_component, n).or_else(|| b_components.next());
+            }
+            _ => return Err("Operations have different lengths".to_string()),
+        }
+    }
+    Ok((a_prime, b_prime))
+}
+
+// The single operation doing what a and then b do
+pub fn compose(a: &TextOperation, b: &TextOperation) -> Result<TextOperation, String> {
+    if a.target_len != b.base_len {
+        return Err("The second operation must apply to the result of the first".to_string());
+    }
+
+    let mut composed = TextOperation::new();
+    let mut a_components = a.components.iter().cloned();
+    let mut b_components = b.components.iter().cloned();
+    let mut a_current = a_components.next();
+    let mut b_current = b_components.next();
+
+    loop {
+        match (&a_current, &b_current) {
+            (None, None) => break,
+            (Some(Component::Delete(n)), _) => {
+                composed.delete(*n);
+                a_current = a_components.next();
+            }
+            (_, Some(Component::Insert(text, _))) => {
+                composed.insert(text);
+                b_current = b_components.next();
+            }
+            (Some(a_component), Some(b_component)) => {
+                let n = component_len(a_component).min(component_len(b_component));
+                match (a_component, b_component) {
+                    (Component::Retain(_), Component::Retain(_)) => composed.retain(n),
+                    (Component::Retain(_), Component::Delete(_)) => composed.delete(n),
+                    (Component::Insert(text, _), Component::Retain(_)) => {
+                        composed.insert(&split_utf16(text, n).0)
+                    }
+                    // inserted, then deleted again
+                    (Component::Insert(..), Component::Delete(_)) => {}
+                    _ => unreachable!("a's deletes and b's inserts are handled above"),
+                }
+                a_current = shorten(a_component, n).or_else(|| a_components.next());
+                b_current = shorten(b_component, n).or_else(|| b_components.next());
+            }
+            _ => return Err("Operations have different lengths".to_string()),
+        }
+    }
+    Ok(composed)
+}
+
+fn component_len(component: &Component) -> usize {
+    match component {
+        Component::Retain(n) | Component::Delete(n) | Component::Insert(_, n) => *n,
+    }
+}
+
+// What's left of a component after consuming n of it
+fn shorten(component: &Component, n: usize) -> Option<Component> {
+    match component {
+        Component::Retain(len) if *len > n => Some(Component::Retain(len - n)),
+        Component::Delete(len) if *len > n => Some(Component::Delete(len - n)),
+        Component::Insert(text, len) if *len > n => {
+            Some(Component::Insert(split_utf16(text, n).1, len - n))
+        }
+        _ => None,
+    }
+}
+
+// Splits after n UTF-16 code units
+fn split_utf16(text: &str, n: usize) -> (String, String) {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    (
+        String::from_utf16_lossy(&units[..n]),
+        String::from_utf16_lossy(&units[n..]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(json: &str) -> TextOperation {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn to_json(operation: &TextOperation) -> String {
+        serde_json::to_string(operation).unwrap()
+    }
+
+    // A tiny deterministic generator, so the random cases are the same on
+    // every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n.max(1) as u64) as usize
+        }
+
+        fn text(&mut self, len: usize) -> String {
+            (0..len)
+                .map(|_| ['a', 'b', 'c', ' ', 'é', '😀'][self.below(6)])
+                .collect()
+        }
+
+        // a random operation on text, like ot.js's test helper
+        fn operation(&mut self, text: &str) -> TextOperation {
+            let units: Vec<u16> = text.encode_utf16().collect();
+            let mut operation = TextOperation::new();
+            let mut index = 0;
+            while index < units.len() {
+                let mut n = 1 + self.below((units.len() - index).min(5));
+                // never cut a surrogate pair in half
+                if (0xDC00..0xE000).contains(units.get(index + n).unwrap_or(&0)) {
+                    n += 1;
+                }
+                match self.below(3) {
+                    0 => operation.retain(n),
+                    1 => operation.delete(n),
+                    _ => {
+                        let len = 1 + self.below(4);
+                        operation.insert(&self.text(len));
+                        operation.retain(n);
+                    }
+                }
+                index += n;
+            }
+            if self.below(2) == 0 {
+                let len = 1 + self.below(4);
+                operation.insert(&self.text(len));
+            }
+            operation
+        }
+    }
+
+    #[test]
+    fn wire_format() {
+        // ot.js: fromJSON([2, -1, -1, 'cde']) merges the deletes
+        let operation = op(r#"[2, -1, -1, "cde"]"#);
+        assert_eq!((operation.base_len(), operation.target_len()), (4, 5));
+        assert_eq!(to_json(&operation), r#"[2,"cde",-2]"#);
+        assert_eq!(to_json(&op(r#"[3, "a", "b", 1, 1]"#)), r#"[3,"ab",2]"#);
+        assert!(serde_json::from_str::<TextOperation>("[0]").is_err());
+        assert!(serde_json::from_str::<TextOperation>("[1, true]").is_err());
+    }
+
+    #[test]
+    fn lengths_that_overflow_are_rejected() {
+        let max = i64::MAX;
+        assert!(
+            serde_json::from_str::<TextOperation>(&format!("[{max}, {max}, {max}, 5]")).is_err()
+        );
+        assert!(
+            serde_json::from_str::<TextOperation>(&format!("[-{max}, -{max}, -{max}]")).is_err()
+        );
+        assert!(serde_json::from_str::<TextOperation>("[18446744073709551615, 5]").is_err());
+        assert_eq!(op("[3]").transform_index(usize::MAX), usize::MAX);
+        assert_eq!(op(r#"["ab"]"#).transform_index(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn apply() {
+        // ot.js README: retain(11).insert(" dolor")
+        assert_eq!(
+            op(r#"[11, " dolor"]"#).apply("lorem ipsum").unwrap(),
+            "lorem ipsum dolor"
+        );
+        assert_eq!(op(r#"[1, -1, "x", 1]"#).apply("abc").unwrap(), "axc");
+        // lengths count UTF-16 code units
+        assert_eq!(op(r#"[2, "!", -1]"#).apply("😀é").unwrap(), "😀!");
+        assert!(op("[3]").apply("ab").is_err());
+        assert!(op(r#"[1, "x", 1]"#).apply("😀").is_err());
+    }
+
+    #[test]
+    fn transform_concurrent_inserts() {
+        let a = op(r#"[1, "x", 2]"#);
+        let b = op(r#"[1, "y", 2]"#);
+        let (a_prime, b_prime) = transform(&a, &b).unwrap();
+        // a's text goes first on both sides
+        assert_eq!(b_prime.apply(&a.apply("abc").unwrap()).unwrap(), "axybc");
+        assert_eq!(a_prime.apply(&b.apply("abc").unwrap()).unwrap(), "axybc");
+    }
+
+    #[test]
+    fn transform_overlapping_deletes() {
+        let a = op("[1, -2, 1]");
+        let b = op("[2, -2]");
+        let (a_prime, b_prime) = transform(&a, &b).unwrap();
+        assert_eq!(to_json(&a_prime), "[1,-1]");
+        assert_eq!(to_json(&b_prime), "[1,-1]");
+        assert_eq!(b_prime.apply(&a.apply("abcd").unwrap()).unwrap(), "a");
+        assert!(transform(&op("[2]"), &op("[3]")).is_err());
+    }
+
+    #[test]
+    fn compose_insert_then_delete() {
+        let a = op(r#"[1, "xyz", 2]"#);
+        let b = op(r#"[2, -2, "!", 2]"#);
+        let composed = compose(&a, &b).unwrap();
+        assert_eq!(to_json(&composed), r#"[1,"x!",2]"#);
+        assert_eq!(
+            composed.apply("abc").unwrap(),
+            b.apply(&a.apply("abc").unwrap()).unwrap()
+        );
+        assert!(compose(&op("[3]"), &op("[2]")).is_err());
+    }
+
+    // ot.js's randomized checks: apply(compose(a, b)) == apply(b, apply(a)),
+    // and transform converges
+    #[test]
+    fn random_compose_and_transform() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let len = rng.below(20);
+            let text = rng.text(len);
+            let a = rng.operation(&text);
+            let b = rng.operation(&text);
+            assert_eq!(
+                a.apply(&text).unwrap().encode_utf16().count(),
+                a.target_len()
+            );
+
+            let (a_prime, b_prime) = transform(&a, &b).unwrap();
+            let after_a = a.apply(&text).unwrap();
+            let after_b = b.apply(&text).unwrap();
+            assert_eq!(
+                b_prime.apply(&after_a).unwrap(),
+                a_prime.apply(&after_b).unwrap()
+            );
+
+            let c = rng.operation(&after_a);
+            let composed = compose(&a, &c).unwrap();
+            assert_eq!(composed.apply(&text).unwrap(), c.apply(&after_a).unwrap());
+
+            let diff = TextOperation::from_diff(&text, &after_a);
+            assert_eq!(diff.apply(&text).unwrap(), after_a);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Per-client queue of push events waiting to be written to the socket, so a
+// slow client can't hold up the broadcast for everyone else. While events
+// wait, newer ones make older ones for the same file redundant and replace
+// them. If the queue overflows anyway it is dropped and the client gets a
+// resync with the current state instead.
+use crate::protocol::ServerMessage;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+pub enum Outgoing {
+    Message(ServerMessage),
+    // events were dropped; the reason is passed on to the client
+    Resync(String),
+    // the server is shutting down and everything queued has been sent
+    Close,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<ServerMessage>,
+    // set when the queue was dropped, until the resync is taken
+    resync: Option<String>,
+    // nothing more is queued after close()
+    closing: bool,
+}
+
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            capacity,
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, message: ServerMessage) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            // the resync sends the state as of when it's taken, which includes
+            // anything arriving now
+            if queue.resync.is_some() || queue.closing {
+                return;
+            }
+            merge(&mut queue.messages, message);
+            if queue.messages.len() > self.capacity {
+                queue.messages.clear();
+                queue.resync = Some(format!("More than {} updates were waiting", self.capacity));
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    // Drops everything queued, e.g. when the client missed broadcast events
+    pub fn overflow(&self, reason: String) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.messages.clear();
+            queue.resync = Some(reason);
+        }
+        self.notify.notify_one();
+    }
+
+    // The messages already queued are still sent, then Close
+    pub fn close(&self) {
+        self.queue.lock().unwrap().closing = true;
+        self.notify.notify_one();
+    }
+
+    // Waits for the next thing to send
+    pub async fn next(&self) -> Outgoing {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(reason) = queue.resync.take() {
+                    return Outgoing::Resync(reason);
+                }
+                if let Some(message) = queue.messages.pop_front() {
+                    return Outgoing::Message(message);
+                }
+                if queue.closing {
+                    return Outgoing::Close;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+// Adds a message to the queue, dropping or folding in the queued messages it
+// makes redundant. Collaborative edits are never merged: clients must see
+// every operation in order.
+fn merge(messages: &mut VecDeque<ServerMessage>, message: ServerMessage) {
+    match &message {
+        // the whole render replaces any update still waiting for the file
+        ServerMessage::ContentUpdate { path, .. } => messages.retain(|queued| {
+            !matches!(queued, ServerMessage::ContentUpdate { path: p, .. } | ServerMessage::ContentPatch { path: p, .. } if p == path)
+        }),
+        // consecutive patches chain into one, their ops applied in order
+        ServerMessage::ContentPatch {
+            path,
+            version,
+            base_version,
+            ops,
+        } => {
+            let last = messages.iter_mut().rev().find(|queued| {
+                matches!(queued, ServerMessage::ContentUpdate { path: p, .. } | ServerMessage::ContentPatch { path: p, .. } if p == path)
+            });
+            if let Some(ServerMessage::ContentPatch {
+                version: queued_version,
+                ops: queued_ops,
+                ..
+            }) = last
+                && *queued_version == *base_version
+            {
+                queued_ops.extend(ops.iter().cloned());
+                *queued_version = *version;
+                return;
+            }
+        }
+        ServerMessage::ScrollTo { path, .. } => {
+            messages.retain(|queued| !matches!(queued, ServerMessage::ScrollTo { path: p, .. } if p == path))
+        }
+        ServerMessage::PeerUpdate { path, peer } => messages.retain(|queued| {
+            !matches!(queued, ServerMessage::PeerUpdate { path: p, peer: q } if p == path && q.client_id == peer.client_id)
+        }),
+        ServerMessage::ClientUpdated { client } => messages.retain(|queued| {
+            !matches!(queued, ServerMessage::ClientUpdated { client: c } if c.client_id == client.client_id)
+        }),
+        _ => {}
+    }
+    messages.push_back(message);
+}
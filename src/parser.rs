@@ -1,136 +1,965 @@
-use crate::data::{Block, Document, Element};
+use crate::data::{Alignment, Block, Document, Element, ListItem};
+
+// Which markdown extensions to recognise on top of CommonMark. An extension
+// also has to be compiled in through its cargo feature to have any effect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub tables: bool,
+    pub strikethrough: bool,
+    pub tasklists: bool,
+    pub footnotes: bool,
+    pub math: bool,
+    pub front_matter: bool,
+    pub raw_html: bool,
+    pub includes: bool,
+    // CommonMark's rules where markdawn's own looser ones differ: headings
+    // need a space after the hashes, paragraph lines keep their line breaks,
+    // and block quotes, indented code and setext headings are recognised
+    pub commonmark: bool,
+}
+
+impl ParseOptions {
+    // extension names as used in markdawn.toml
+    pub const EXTENSIONS: [&'static str; 8] = [
+        "tables",
+        "strikethrough",
+        "tasklists",
+        "footnotes",
+        "math",
+        "front_matter",
+        "raw_html",
+        "includes",
+    ];
+
+    // plain CommonMark, every extension off
+    pub fn commonmark() -> Self {
+        Self {
+            tables: false,
+            strikethrough: false,
+            tasklists: false,
+            footnotes: false,
+            math: false,
+            front_matter: false,
+            raw_html: false,
+            includes: false,
+            commonmark: true,
+        }
+    }
+
+    // CommonMark plus exactly the named extensions
+    pub fn with_extensions<S: AsRef<str>>(names: &[S]) -> Result<Self, String> {
+        let mut options = Self::commonmark();
+        for name in names {
+            options.set_extension(name.as_ref(), true)?;
+        }
+        Ok(options)
+    }
+
+    pub fn set_extension(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let flag = match name {
+            "tables" => &mut self.tables,
+            "strikethrough" => &mut self.strikethrough,
+            "tasklists" => &mut self.tasklists,
+            "footnotes" => &mut self.footnotes,
+            "math" => &mut self.math,
+            "front_matter" => &mut self.front_matter,
+            "raw_html" => &mut self.raw_html,
+            "includes" => &mut self.includes,
+            _ => {
+                return Err(format!(
+                    "Unknown markdown extension '{}', expected one of: {}",
+                    name,
+                    Self::EXTENSIONS.join(", ")
+                ));
+            }
+        };
+        *flag = enabled;
+        Ok(())
+    }
+
+    // Runtime flags masked by the extensions that were compiled in
+    fn effective(&self) -> Self {
+        Self {
+            tables: self.tables && cfg!(feature = "tables"),
+            strikethrough: self.strikethrough && cfg!(feature = "strikethrough"),
+            tasklists: self.tasklists && cfg!(feature = "tasklists"),
+            footnotes: self.footnotes && cfg!(feature = "footnotes"),
+            math: self.math && cfg!(feature = "math"),
+            front_matter: self.front_matter && cfg!(feature = "front-matter"),
+            raw_html: self.raw_html && cfg!(feature = "raw-html"),
+            includes: self.includes && cfg!(feature = "includes"),
+            commonmark: self.commonmark,
+        }
+    }
+}
+
+// Every extension on (as far as it is compiled in), with markdawn's own rules
+// for headings and paragraphs
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            tables: true,
+            strikethrough: true,
+            tasklists: true,
+            footnotes: true,
+            math: true,
+            front_matter: true,
+            raw_html: true,
+            includes: true,
+            commonmark: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 enum LineType {
     Title { level: u8, content: String },
+    Fence { marker: String, info: String },
+    MathFence,
+    ListItem { start: Option<u64>, content: String },
+    FootnoteDef { label: String, content: String },
+    Html,
+    Include(String),
+    Rule,
+    Quote,
     PlainText(String),
     Empty,
 }
 
-pub fn parse_markdown(text: &str) -> Result<Document, String> {
+pub fn parse_markdown(text: &str, options: &ParseOptions) -> Result<Document, String> {
+    let options = options.effective();
+    let lines: Vec<&str> = text.lines().collect();
     let mut blocks = Vec::new();
-    let mut current_paragraph: Option<Vec<String>> = None;
+    let mut source_lines = Vec::new();
+    let mut footnotes = Vec::new();
+    // (first line number, lines)
+    let mut current_paragraph: Option<(usize, Vec<String>)> = None;
+
+    let (front_matter, mut i) = if options.front_matter {
+        split_front_matter(&lines)
+    } else {
+        (None, 0)
+    };
 
     // input based on lines
-    for line in text.lines() {
-        let line_type = parse_line(line);
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        // 1-based number of `line`
+        let line_number = i;
 
-        match line_type {
-            LineType::Empty => {
-                if let Some(paragraph_lines) = current_paragraph.take() {
-                    let combined_text = paragraph_lines.join("");
-                    let elements = parse_inline(&combined_text);
-                    blocks.push(Block::Paragraph(elements));
-                }
+        // four columns of indentation make code, unless they continue a paragraph
+        let indented = options.commonmark && indentation(line) >= 4 && !line.trim().is_empty();
+        if indented && current_paragraph.is_none() {
+            let mut code_lines = vec![strip_indentation(line, 4)];
+            while i < lines.len() && (indentation(lines[i]) >= 4 || lines[i].trim().is_empty()) {
+                code_lines.push(strip_indentation(lines[i], 4));
+                i += 1;
             }
-            LineType::Title { level, content } => {
-                if let Some(paragraph_lines) = current_paragraph.take() {
-                    let combined_text = paragraph_lines.join("");
-                    let elements = parse_inline(&combined_text);
-                    blocks.push(Block::Paragraph(elements));
+            // blank lines after the code aren't part of it
+            while code_lines.last().is_some_and(|line| line.trim().is_empty()) {
+                code_lines.pop();
+            }
+            source_lines.push(line_number);
+            blocks.push(Block::CodeBlock {
+                language: None,
+                code: code_lines.join("\n"),
+            });
+            continue;
+        }
+
+        // `===` or `---` under a paragraph turns it into a heading
+        if options.commonmark
+            && !indented
+            && let Some(level) = setext_level(line)
+            && let Some((first_line, paragraph_lines)) = current_paragraph.take()
+        {
+            source_lines.push(first_line);
+            blocks.push(Block::Title {
+                level,
+                content: parse_inline(&paragraph_lines.join("\n"), &options),
+            });
+            continue;
+        }
+
+        // a table starts with a header row directly followed by a delimiter row
+        if options.tables
+            && line.contains('|')
+            && i < lines.len()
+            && let Some(alignments) = parse_delimiter_row(lines[i])
+        {
+            let header = split_row(line);
+            if header.len() == alignments.len() {
+                flush_paragraph(&mut current_paragraph, &mut blocks, &mut source_lines, &options);
+                i += 1;
+                let mut rows = Vec::new();
+                while i < lines.len() && lines[i].contains('|') && !lines[i].trim().is_empty() {
+                    let mut cells = split_row(lines[i]);
+                    cells.resize(alignments.len(), String::new());
+                    rows.push(cells.iter().map(|cell| parse_inline(cell, &options)).collect());
+                    i += 1;
                 }
+                source_lines.push(line_number);
+                blocks.push(Block::Table {
+                    alignments,
+                    header: header.iter().map(|cell| parse_inline(cell, &options)).collect(),
+                    rows,
+                });
+                continue;
+            }
+        }
 
-                let title_elements = parse_inline(&content);
+        let line_type = match indented {
+            true => LineType::PlainText(line.trim().to_string()),
+            false => parse_line(line, &options),
+        };
+        if !matches!(line_type, LineType::PlainText(_)) {
+            flush_paragraph(&mut current_paragraph, &mut blocks, &mut source_lines, &options);
+        }
+        if !matches!(line_type, LineType::Empty | LineType::PlainText(_) | LineType::FootnoteDef { .. }) {
+            source_lines.push(line_number);
+        }
+
+        match line_type {
+            LineType::Empty => {}
+            LineType::Title { level, content } => {
+                let title_elements = parse_inline(&content, &options);
                 blocks.push(Block::Title {
                     level,
                     content: title_elements,
                 });
             }
+            LineType::Fence { marker, info } => {
+                let mut code_lines = Vec::new();
+                while i < lines.len() && !lines[i].trim_start().starts_with(&marker) {
+                    code_lines.push(lines[i]);
+                    i += 1;
+                }
+                i += 1; // closing fence (or end of input)
+                let language = info.split_whitespace().next().map(str::to_string);
+                blocks.push(Block::CodeBlock {
+                    language,
+                    code: code_lines.join("\n"),
+                });
+            }
+            LineType::MathFence => {
+                let mut math_lines = Vec::new();
+                while i < lines.len() && lines[i].trim() != "$$" {
+                    math_lines.push(lines[i]);
+                    i += 1;
+                }
+                i += 1;
+                blocks.push(Block::Math(math_lines.join("\n")));
+            }
+            LineType::ListItem { start, content } => {
+                let mut items = vec![content];
+                while i < lines.len() {
+                    match parse_line(lines[i], &options) {
+                        LineType::ListItem { start: next, content } if next.is_some() == start.is_some() => {
+                            items.push(content);
+                        }
+                        // lazy continuation of the previous item
+                        LineType::PlainText(content) => {
+                            let last = items.last_mut().expect("list has an item");
+                            last.push('\n');
+                            last.push_str(&content);
+                        }
+                        _ => break,
+                    }
+                    i += 1;
+                }
+                let items = items
+                    .into_iter()
+                    .map(|content| parse_list_item(&content, &options))
+                    .collect();
+                blocks.push(Block::List { start, items });
+            }
+            LineType::FootnoteDef { label, content } => {
+                footnotes.push((
+                    line_number,
+                    Block::Footnote {
+                        label,
+                        content: parse_inline(&content, &options),
+                    },
+                ));
+            }
+            LineType::Html => {
+                // raw HTML runs until the next blank line
+                let mut html_lines = vec![line];
+                while i < lines.len() && !lines[i].trim().is_empty() {
+                    html_lines.push(lines[i]);
+                    i += 1;
+                }
+                blocks.push(Block::Html(html_lines.join("\n")));
+            }
+            LineType::Include(path) => blocks.push(Block::Include(path)),
+            LineType::Rule => blocks.push(Block::Rule),
+            LineType::Quote => {
+                // `>` lines, and paragraph lines continued without one
+                let mut quote_lines = vec![strip_quote_marker(line)];
+                while i < lines.len() {
+                    let next = lines[i];
+                    let previous = quote_lines.last().map_or("", String::as_str);
+                    if indentation(next) < 4 && next.trim_start().starts_with('>') {
+                        quote_lines.push(strip_quote_marker(next));
+                    } else if matches!(parse_line(previous, &options), LineType::PlainText(_))
+                        && matches!(parse_line(next, &options), LineType::PlainText(_))
+                    {
+                        quote_lines.push(next.to_string());
+                    } else {
+                        break;
+                    }
+                    i += 1;
+                }
+                let inner_options = ParseOptions {
+                    front_matter: false,
+                    ..options.clone()
+                };
+                let quoted = parse_markdown(&quote_lines.join("\n"), &inner_options)?;
+                blocks.push(Block::BlockQuote(quoted.blocks));
+            }
             LineType::PlainText(content) => match &mut current_paragraph {
-                Some(paragraph_lines) => {
+                Some((_, paragraph_lines)) => {
                     paragraph_lines.push(content);
                 }
                 None => {
-                    current_paragraph = Some(vec![content]);
+                    current_paragraph = Some((line_number, vec![content]));
                 }
             },
         }
     }
     // the last paragraph line
-    if let Some(paragraph_lines) = current_paragraph {
-        let combined_text = paragraph_lines.join("");
-        let elements = parse_inline(&combined_text);
+    flush_paragraph(&mut current_paragraph, &mut blocks, &mut source_lines, &options);
+
+    // footnote definitions are rendered at the end, wherever they were written
+    for (line_number, footnote) in footnotes {
+        source_lines.push(line_number);
+        blocks.push(footnote);
+    }
+
+    Ok(Document {
+        blocks,
+        front_matter,
+        source_lines,
+    })
+}
+
+fn flush_paragraph(
+    current_paragraph: &mut Option<(usize, Vec<String>)>,
+    blocks: &mut Vec<Block>,
+    source_lines: &mut Vec<usize>,
+    options: &ParseOptions,
+) {
+    if let Some((line_number, paragraph_lines)) = current_paragraph.take() {
+        let separator = if options.commonmark { "\n" } else { "" };
+        let combined_text = paragraph_lines.join(separator);
+        let elements = parse_inline(&combined_text, options);
+        source_lines.push(line_number);
         blocks.push(Block::Paragraph(elements));
     }
+}
 
-    Ok(Document { blocks })
+// `---` on the first line up to the next `---` or `...`
+fn split_front_matter(lines: &[&str]) -> (Option<String>, usize) {
+    if lines.first().map(|line| line.trim_end()) != Some("---") {
+        return (None, 0);
+    }
+    match lines[1..]
+        .iter()
+        .position(|line| matches!(line.trim_end(), "---" | "..."))
+    {
+        Some(end) => (Some(lines[1..=end].join("\n")), end + 2),
+        None => (None, 0),
+    }
 }
 
-fn parse_line(line: &str) -> LineType {
+fn parse_line(line: &str, options: &ParseOptions) -> LineType {
     let trimmed = line.trim();
     if trimmed.is_empty() {
         return LineType::Empty;
     }
 
+    if options.commonmark && trimmed.starts_with('>') {
+        return LineType::Quote;
+    }
+
     if trimmed.starts_with('#') {
         let level = trimmed.chars().take_while(|&c| c == '#').count() as u8;
+        let rest = &trimmed[level as usize..];
 
-        if level > 0 && level <= 6 {
-            let content = trimmed
-                .chars()
-                .skip(level as usize)
-                .collect::<String>()
-                .trim()
-                .to_string();
+        if options.commonmark {
+            if level <= 6 && (rest.is_empty() || rest.starts_with([' ', '\t'])) {
+                let content = strip_closing_hashes(rest.trim()).to_string();
+                return LineType::Title { level, content };
+            }
+        // `#Heading` without a space is a heading too
+        } else if level > 0 && level <= 6 {
+            let content = rest.trim().to_string();
 
             return LineType::Title { level, content };
         }
     }
 
+    for marker in ["```", "~~~"] {
+        if let Some(info) = trimmed.strip_prefix(marker) {
+            let fence_len = marker.len() + info.chars().take_while(|&c| c == marker.as_bytes()[0] as char).count();
+            return LineType::Fence {
+                marker: trimmed[..fence_len].to_string(),
+                info: trimmed[fence_len..].trim().to_string(),
+            };
+        }
+    }
+
+    if options.math && trimmed == "$$" {
+        return LineType::MathFence;
+    }
+
+    if is_rule(trimmed) {
+        return LineType::Rule;
+    }
+
+    if let Some(content) = ["- ", "* ", "+ "].iter().find_map(|m| trimmed.strip_prefix(m)) {
+        return LineType::ListItem {
+            start: None,
+            content: content.trim().to_string(),
+        };
+    }
+
+    let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+    if (1..=9).contains(&digits)
+        && let Some(content) = trimmed[digits..]
+            .strip_prefix(". ")
+            .or_else(|| trimmed[digits..].strip_prefix(") "))
+    {
+        return LineType::ListItem {
+            start: trimmed[..digits].parse().ok(),
+            content: content.trim().to_string(),
+        };
+    }
+
+    if options.footnotes
+        && let Some(rest) = trimmed.strip_prefix("[^")
+        && let Some((label, content)) = rest.split_once("]:")
+        && !label.is_empty()
+    {
+        return LineType::FootnoteDef {
+            label: label.to_string(),
+            content: content.trim().to_string(),
+        };
+    }
+
+    if options.raw_html && is_html_start(trimmed) {
+        return LineType::Html;
+    }
+
+    // `{{include chapters/intro.md}}` on a line of its own
+    if options.includes
+        && let Some(path) = trimmed
+            .strip_prefix("{{include ")
+            .and_then(|rest| rest.strip_suffix("}}"))
+            .map(str::trim)
+        && !path.is_empty()
+    {
+        return LineType::Include(path.to_string());
+    }
+
     LineType::PlainText(trimmed.to_string())
 }
 
-fn parse_inline(text: &str) -> Vec<Element> {
+// `# Title ##` -> `Title`; hashes that are part of the text stay
+fn strip_closing_hashes(content: &str) -> &str {
+    let without = content.trim_end_matches('#');
+    if without.is_empty() {
+        ""
+    } else if without.ends_with([' ', '\t']) {
+        without.trim_end()
+    } else {
+        content
+    }
+}
+
+// The heading level of a setext underline: 1 for `===`, 2 for `---`
+fn setext_level(line: &str) -> Option<u8> {
+    let trimmed = line.trim();
+    if !trimmed.is_empty() && trimmed.chars().all(|c| c == '=') {
+        Some(1)
+    } else if !trimmed.is_empty() && trimmed.chars().all(|c| c == '-') {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+// Leading whitespace in columns, tabs stopping at multiples of four
+fn indentation(line: &str) -> usize {
+    let mut column = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => column += 1,
+            '\t' => column += 4 - column % 4,
+            _ => break,
+        }
+    }
+    column
+}
+
+// Removes up to `columns` of leading whitespace; what's left of a tab that
+// straddles the cut becomes spaces
+fn strip_indentation(line: &str, columns: usize) -> String {
+    let mut column = 0;
+    for (index, c) in line.char_indices() {
+        if column >= columns {
+            return line[index..].to_string();
+        }
+        match c {
+            ' ' => column += 1,
+            '\t' => {
+                let next = column + 4 - column % 4;
+                if next > columns {
+                    return " ".repeat(next - columns) + &line[index + 1..];
+                }
+                column = next;
+            }
+            _ => return line[index..].to_string(),
+        }
+    }
+    String::new()
+}
+
+// `> text` -> `text`, keeping any further indentation
+fn strip_quote_marker(line: &str) -> String {
+    let rest = line.trim_start().strip_prefix('>').unwrap_or(line);
+    rest.strip_prefix([' ', '\t']).unwrap_or(rest).to_string()
+}
+
+// three or more `-`, `*` or `_`, optionally separated by spaces
+fn is_rule(line: &str) -> bool {
+    let chars: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    chars.len() >= 3 && matches!(chars[0], '-' | '*' | '_') && chars.iter().all(|&c| c == chars[0])
+}
+
+fn is_html_start(line: &str) -> bool {
+    let mut chars = line.chars();
+    chars.next() == Some('<')
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!')
+}
+
+fn parse_list_item(content: &str, options: &ParseOptions) -> ListItem {
+    if options.tasklists {
+        for (marker, checked) in [("[ ] ", false), ("[x] ", true), ("[X] ", true)] {
+            if let Some(rest) = content.strip_prefix(marker) {
+                return ListItem {
+                    checked: Some(checked),
+                    content: parse_inline(rest, options),
+                };
+            }
+        }
+    }
+    ListItem {
+        checked: None,
+        content: parse_inline(content, options),
+    }
+}
+
+// `| a | b |` -> ["a", "b"]
+fn split_row(line: &str) -> Vec<String> {
+    let trimmed = line.trim();
+    let trimmed = trimmed.strip_prefix('|').unwrap_or(trimmed);
+    let trimmed = trimmed.strip_suffix('|').unwrap_or(trimmed);
+    trimmed.split('|').map(|cell| cell.trim().to_string()).collect()
+}
+
+// `|:---|:---:|---:|` -> alignments, None if the line isn't a delimiter row
+fn parse_delimiter_row(line: &str) -> Option<Vec<Alignment>> {
+    if !line.contains('-') {
+        return None;
+    }
+    split_row(line)
+        .iter()
+        .map(|cell| {
+            let left = cell.starts_with(':');
+            let right = cell.ends_with(':');
+            let dashes = cell.trim_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            Some(match (left, right) {
+                (true, true) => Alignment::Center,
+                (true, false) => Alignment::Left,
+                (false, true) => Alignment::Right,
+                (false, false) => Alignment::None,
+            })
+        })
+        .collect()
+}
+
+fn parse_inline(text: &str, options: &ParseOptions) -> Vec<Element> {
     let mut elements = Vec::new();
+    let mut plain = String::new();
     let mut current_pos = 0;
     let chars: Vec<char> = text.chars().collect();
 
     while current_pos < chars.len() {
-        if current_pos + 1 < chars.len()
-            && (chars[current_pos] == '*')
-            && chars[current_pos + 1] == '*'
-        {
-            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 2, "**") {
-                let bold_content: String = chars[(current_pos + 2)..end_pos].iter().collect();
-                elements.push(Element::Bold(bold_content));
-                current_pos = end_pos + 2;
+        let c = chars[current_pos];
+        let next = chars.get(current_pos + 1).copied();
+        let mut matched: Option<(Element, usize)> = None;
+
+        if c == '\\' && next.is_some_and(|n| n.is_ascii_punctuation()) {
+            plain.push(chars[current_pos + 1]);
+            current_pos += 2;
+            continue;
+        } else if c == '`' {
+            let ticks = chars[current_pos..].iter().take_while(|&&t| t == '`').count();
+            let fence = "`".repeat(ticks);
+            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + ticks, &fence) {
+                let code: String = chars[(current_pos + ticks)..end_pos].iter().collect();
+                let code = code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')).unwrap_or(&code);
+                matched = Some((Element::Code(code.to_string()), end_pos + ticks));
             } else {
-                elements.push(Element::PlainText("*".to_string()));
-                current_pos += 1;
+                // an unmatched run of backticks is literal
+                plain.push_str(&fence);
+                current_pos += ticks;
+                continue;
+            }
+        } else if (c == '*' || c == '_') && next == Some(c) {
+            let pattern: String = [c, c].iter().collect();
+            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 2, &pattern)
+                && is_flanked(&chars, current_pos + 2, end_pos)
+            {
+                let bold_content: String = chars[(current_pos + 2)..end_pos].iter().collect();
+                matched = Some((Element::Bold(bold_content), end_pos + 2));
             }
-        } else if chars[current_pos] == '*' {
-            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 1, "*") {
+        } else if c == '*' || (c == '_' && !is_word_char(current_pos.checked_sub(1).map(|p| chars[p]))) {
+            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 1, &c.to_string())
+                && is_flanked(&chars, current_pos + 1, end_pos)
+            {
                 let italic_content: String = chars[(current_pos + 1)..end_pos].iter().collect();
-                elements.push(Element::Italic(italic_content));
-                current_pos = end_pos + 1;
+                matched = Some((Element::Italic(italic_content), end_pos + 1));
             }
-        } else {
-            let start_pos = current_pos;
-            while current_pos < chars.len() && chars[current_pos] != '*' {
-                current_pos += 1
+        } else if c == '~' && next == Some('~') && options.strikethrough {
+            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 2, "~~")
+                && is_flanked(&chars, current_pos + 2, end_pos)
+            {
+                let struck: String = chars[(current_pos + 2)..end_pos].iter().collect();
+                matched = Some((Element::Strikethrough(struck), end_pos + 2));
             }
+        } else if c == '$' && options.math && next.is_some_and(|n| !n.is_whitespace()) {
+            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 1, "$")
+                && !chars[end_pos - 1].is_whitespace()
+            {
+                let math: String = chars[(current_pos + 1)..end_pos].iter().collect();
+                matched = Some((Element::Math(math), end_pos + 1));
+            }
+        } else if c == '[' && next == Some('^') && options.footnotes {
+            if let Some(end_pos) = find_closing_pattern(&chars, current_pos + 2, "]")
+                && end_pos > current_pos + 2
+            {
+                let label: String = chars[(current_pos + 2)..end_pos].iter().collect();
+                matched = Some((Element::FootnoteRef(label), end_pos + 1));
+            }
+        } else if c == '[' {
+            matched = parse_link(&chars, current_pos);
+        } else if c == '<'
+            && options.raw_html
+            && next.is_some_and(|n| n.is_ascii_alphabetic() || n == '/' || n == '!')
+            && let Some(end_pos) = find_closing_pattern(&chars, current_pos + 1, ">")
+        {
+            let tag: String = chars[current_pos..=end_pos].iter().collect();
+            matched = Some((Element::Html(tag), end_pos + 1));
+        }
 
-            if start_pos < current_pos {
-                let plain_text: String = chars[start_pos..current_pos].iter().collect();
-                elements.push(Element::PlainText(plain_text));
+        match matched {
+            Some((element, end)) => {
+                if !plain.is_empty() {
+                    elements.push(Element::PlainText(std::mem::take(&mut plain)));
+                }
+                elements.push(element);
+                current_pos = end;
+            }
+            None => {
+                plain.push(c);
+                current_pos += 1;
             }
         }
     }
 
-    if elements.is_empty() {
-        elements.push(Element::PlainText(text.to_string()));
+    if !plain.is_empty() || elements.is_empty() {
+        elements.push(Element::PlainText(plain));
     }
     elements
 }
 
+// `[text](url)` starting at start_pos
+fn parse_link(chars: &[char], start_pos: usize) -> Option<(Element, usize)> {
+    let text_end = find_closing_pattern(chars, start_pos + 1, "]")?;
+    if chars.get(text_end + 1) != Some(&'(') {
+        return None;
+    }
+    let (url, end) = parse_destination(chars, text_end + 2)?;
+    let text: String = chars[(start_pos + 1)..text_end].iter().collect();
+    Some((Element::Link { text, url }, end))
+}
+
+// The url of a link and where the link ends, after its `)`. Either `<...>`,
+// or up to the `)` that balances the link's `(`, so a url can contain
+// parentheses, like `wiki/Foo_(bar)`. Backslash escapes are resolved.
+fn parse_destination(chars: &[char], start_pos: usize) -> Option<(String, usize)> {
+    let mut url = String::new();
+    let mut pos = start_pos;
+    while chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+        pos += 1;
+    }
+
+    if chars.get(pos) == Some(&'<') {
+        pos += 1;
+        loop {
+            match *chars.get(pos)? {
+                '>' => break,
+                '<' | '\n' => return None,
+                '\\' if chars.get(pos + 1).is_some_and(|c| c.is_ascii_punctuation()) => {
+                    url.push(chars[pos + 1]);
+                    pos += 2;
+                    continue;
+                }
+                c => url.push(c),
+            }
+            pos += 1;
+        }
+        pos += 1;
+        while chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+            pos += 1;
+        }
+        return (chars.get(pos) == Some(&')')).then_some((url, pos + 1));
+    }
+
+    let mut depth = 0;
+    loop {
+        match *chars.get(pos)? {
+            '(' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            '\\' if chars.get(pos + 1).is_some_and(|c| c.is_ascii_punctuation()) => {
+                url.push(chars[pos + 1]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+        url.push(chars[pos]);
+        pos += 1;
+    }
+    Some((url.trim().to_string(), pos + 1))
+}
+
+// emphasis content must be non-empty and not start or end with whitespace,
+// so `3 * 4 * 5` stays plain text
+fn is_flanked(chars: &[char], start: usize, end: usize) -> bool {
+    end > start && !chars[start].is_whitespace() && !chars[end - 1].is_whitespace()
+}
+
+fn is_word_char(c: Option<char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric())
+}
+
 fn find_closing_pattern(chars: &[char], start_pos: usize, pattern: &str) -> Option<usize> {
     let pattern_chars: Vec<char> = pattern.chars().collect();
     let pattern_len = pattern_chars.len();
 
-    for i in start_pos..=chars.len().saturating_sub(pattern_len) {
-        if chars[i..i + pattern_len] == pattern_chars {
-            return Some(i);
-        }
+    (start_pos..=chars.len().saturating_sub(pattern_len)).find(|&i| chars[i..i + pattern_len] == pattern_chars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(text: &str, options: &ParseOptions) -> String {
+        parse_markdown(text, options).unwrap().to_html()
+    }
+
+    // Cases from the CommonMark spec, https://spec.commonmark.org/0.31.2/
+    #[test]
+    fn atx_headings_need_a_space_after_the_hashes() {
+        let options = ParseOptions::commonmark();
+        assert_eq!(html("# foo", &options), "<h1>foo</h1>");
+        assert_eq!(html("#5 bolt\n\n#hashtag", &options), "<p>#5 bolt</p>\n<p>#hashtag</p>");
+        assert_eq!(html("####### foo", &options), "<p>####### foo</p>");
+        assert_eq!(html("#\tfoo", &options), "<h1>foo</h1>");
+        assert_eq!(html("## foo ##", &options), "<h2>foo</h2>");
+        assert_eq!(html("# foo#", &options), "<h1>foo#</h1>");
+        assert_eq!(html("#", &options), "<h1></h1>");
+    }
+
+    #[test]
+    fn paragraph_lines_keep_their_line_breaks() {
+        let options = ParseOptions::commonmark();
+        assert_eq!(html("aaa\nbbb\n\nccc", &options), "<p>aaa\nbbb</p>\n<p>ccc</p>");
+        assert_eq!(html("  aaa\n bbb", &options), "<p>aaa\nbbb</p>");
+        // indentation doesn't start code inside a paragraph
+        assert_eq!(html("aaa\n             bbb", &options), "<p>aaa\nbbb</p>");
+    }
+
+    #[test]
+    fn setext_headings() {
+        let options = ParseOptions::commonmark();
+        assert_eq!(
+            html("Foo *bar*\n=========\n\nFoo *bar*\n---------", &options),
+            "<h1>Foo <em>bar</em></h1>\n<h2>Foo <em>bar</em></h2>"
+        );
+        assert_eq!(html("Foo\nbar\n===", &options), "<h1>Foo\nbar</h1>");
+        assert_eq!(html("Foo\n\n---", &options), "<p>Foo</p>\n<hr />");
+        assert_eq!(html("===", &options), "<p>===</p>");
+    }
+
+    #[test]
+    fn indented_code_blocks() {
+        let options = ParseOptions::commonmark();
+        let document = parse_markdown("    a simple\n      indented code block\n\n\tchunk2\n\nafter", &options).unwrap();
+        assert!(matches!(
+            &document.blocks[0],
+            Block::CodeBlock { language: None, code } if code == "a simple\n  indented code block\n\nchunk2"
+        ));
+        assert!(matches!(&document.blocks[1], Block::Paragraph(_)));
+        assert_eq!(document.source_lines, vec![1, 6]);
+        // markdawn's own dialect has no indented code
+        assert_eq!(html("    code", &ParseOptions::default()), "<p>code</p>");
+    }
+
+    #[test]
+    fn block_quotes() {
+        let options = ParseOptions::commonmark();
+        assert_eq!(
+            html("> # Foo\n> bar\nbaz", &options),
+            "<blockquote>\n<h1>Foo</h1>\n<p>bar\nbaz</p>\n</blockquote>"
+        );
+        assert_eq!(
+            html("> foo\n\n> bar", &options),
+            "<blockquote>\n<p>foo</p>\n</blockquote>\n<blockquote>\n<p>bar</p>\n</blockquote>"
+        );
+        assert_eq!(
+            html("> foo\n>\n> > bar\n---", &options),
+            "<blockquote>\n<p>foo</p>\n<blockquote>\n<p>bar</p>\n</blockquote>\n</blockquote>\n<hr />"
+        );
+        assert_eq!(html(">", &options), "<blockquote>\n</blockquote>");
+    }
+
+    #[test]
+    fn link_destinations_may_hold_parentheses() {
+        let options = ParseOptions::commonmark();
+        assert_eq!(
+            html("[x](https://en.wikipedia.org/wiki/Foo_(bar)) after", &options),
+            "<p><a href=\"https://en.wikipedia.org/wiki/Foo_(bar)\">x</a> after</p>"
+        );
+        assert_eq!(html("[a](<b)c>)", &options), "<p><a href=\"b)c\">a</a></p>");
+        assert_eq!(html("[a](<my url>)", &options), "<p><a href=\"my url\">a</a></p>");
+        assert_eq!(html("[a](foo\\)\\:)", &options), "<p><a href=\"foo):\">a</a></p>");
+        // unbalanced, so not a link
+        assert_eq!(html("[link](foo(and(bar))", &options), "<p>[link](foo(and(bar))</p>");
+        assert_eq!(html("[a](<b)c)", &options), "<p>[a](&lt;b)c)</p>");
+    }
+
+    #[test]
+    fn inline_emphasis_code_links_and_escapes() {
+        let options = ParseOptions::commonmark();
+        assert_eq!(
+            html("**b** *i* `c` [l](u) \\*x\\*", &options),
+            "<p><strong>b</strong> <em>i</em> <code>c</code> <a href=\"u\">l</a> *x*</p>"
+        );
+        // emphasis needs non-space content next to its markers
+        assert_eq!(html("3 * 4 * 5", &options), "<p>3 * 4 * 5</p>");
+    }
+
+    #[test]
+    fn fences_lists_and_rules() {
+        let options = ParseOptions::commonmark();
+        let document = parse_markdown("```rust\nlet x = 1;\n```\n- a\n- b\n\n1. one\n\n---", &options).unwrap();
+        assert!(matches!(
+            &document.blocks[0],
+            Block::CodeBlock { language: Some(language), code } if language == "rust" && code == "let x = 1;"
+        ));
+        assert!(matches!(&document.blocks[1], Block::List { start: None, items } if items.len() == 2));
+        assert!(matches!(&document.blocks[2], Block::List { start: Some(1), .. }));
+        assert!(matches!(&document.blocks[3], Block::Rule));
+        assert_eq!(document.source_lines, vec![1, 4, 7, 9]);
+    }
+
+    #[test]
+    fn extensions_are_off_in_commonmark() {
+        let options = ParseOptions::commonmark();
+        let document = parse_markdown("| a |\n|---|\n- [ ] todo\n\n~~s~~ $x$ [^1] <b>\n\n{{include a.md}}", &options).unwrap();
+        assert!(!document.blocks.iter().any(|block| matches!(block, Block::Table { .. } | Block::Include(_))));
+        assert!(matches!(&document.blocks[1], Block::List { items, .. } if items[0].checked.is_none()));
+        assert_eq!(html("~~s~~ <b>", &options), "<p>~~s~~ &lt;b&gt;</p>");
+    }
+
+    #[test]
+    fn unknown_extension_names_are_rejected() {
+        assert!(ParseOptions::with_extensions(&["tables", "includes"]).is_ok());
+        assert!(ParseOptions::with_extensions(&["tablez"]).is_err());
+    }
+
+    #[cfg(feature = "tables")]
+    #[test]
+    fn tables() {
+        let options = ParseOptions::with_extensions(&["tables"]).unwrap();
+        let document = parse_markdown("| a | b |\n|:--|--:|\n| 1 | 2 |", &options).unwrap();
+        assert!(matches!(
+            &document.blocks[0],
+            Block::Table { alignments, rows, .. } if alignments == &[Alignment::Left, Alignment::Right] && rows.len() == 1
+        ));
+    }
+
+    #[cfg(feature = "tasklists")]
+    #[test]
+    fn tasklists() {
+        let options = ParseOptions::with_extensions(&["tasklists"]).unwrap();
+        let document = parse_markdown("- [ ] todo\n- [x] done", &options).unwrap();
+        assert!(matches!(
+            &document.blocks[0],
+            Block::List { items, .. } if items[0].checked == Some(false) && items[1].checked == Some(true)
+        ));
+    }
+
+    #[cfg(all(feature = "strikethrough", feature = "math", feature = "raw-html"))]
+    #[test]
+    fn inline_extensions() {
+        let options = ParseOptions::with_extensions(&["strikethrough", "math", "raw_html"]).unwrap();
+        let document = parse_markdown("~~s~~ $x$ <b>", &options).unwrap();
+        let Block::Paragraph(elements) = &document.blocks[0] else {
+            panic!("expected a paragraph");
+        };
+        assert!(matches!(&elements[0], Element::Strikethrough(text) if text == "s"));
+        assert!(matches!(&elements[2], Element::Math(math) if math == "x"));
+        assert!(matches!(&elements[4], Element::Html(tag) if tag == "<b>"));
+    }
+
+    #[cfg(feature = "footnotes")]
+    #[test]
+    fn footnotes_go_to_the_end() {
+        let options = ParseOptions::with_extensions(&["footnotes"]).unwrap();
+        let document = parse_markdown("[^1]: note\n\ntext[^1]", &options).unwrap();
+        assert!(matches!(&document.blocks[0], Block::Paragraph(_)));
+        assert!(matches!(&document.blocks[1], Block::Footnote { label, .. } if label == "1"));
+        assert_eq!(document.source_lines, vec![3, 1]);
+    }
+
+    #[cfg(feature = "front-matter")]
+    #[test]
+    fn front_matter() {
+        let options = ParseOptions::with_extensions(&["front_matter"]).unwrap();
+        let document = parse_markdown("---\ntitle: Hi\n---\n\ntext", &options).unwrap();
+        assert_eq!(document.front_matter.as_deref(), Some("title: Hi"));
+        assert_eq!(document.title().as_deref(), Some("Hi"));
+        assert_eq!(document.source_lines, vec![5]);
+    }
+
+    #[cfg(feature = "includes")]
+    #[test]
+    fn includes_need_a_line_of_their_own() {
+        let options = ParseOptions::with_extensions(&["includes"]).unwrap();
+        let document = parse_markdown("{{include chapters/a.md}}\n\nsee {{include b.md}}", &options).unwrap();
+        assert!(matches!(&document.blocks[0], Block::Include(path) if path == "chapters/a.md"));
+        assert!(matches!(&document.blocks[1], Block::Paragraph(_)));
     }
-    None
 }
@@ -0,0 +1,303 @@
+use crate::data;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+// One step of a block-level diff. Indices refer to the block list as it is
+// after the previous ops have been applied, so ops must be applied in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BlockOp {
+    Insert { index: usize, html: String },
+    Replace { index: usize, html: String },
+    Delete { index: usize },
+}
+
+// What changed between the cached render of a file and a new one
+#[derive(Debug, Clone)]
+pub enum RenderChange {
+    // first render, or the patch wouldn't be smaller than the whole document
+    Full { version: u64, blocks: Vec<String> },
+    Patch {
+        version: u64,
+        base_version: u64,
+        ops: Vec<BlockOp>,
+    },
+    Unchanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderedFile {
+    // bumped on every change, so clients can tell whether a patch applies
+    pub version: u64,
+    pub blocks: Vec<String>,
+    // where each block starts in the source, see Document::source_lines
+    pub source_lines: Vec<usize>,
+}
+
+impl RenderedFile {
+    pub fn block_at_line(&self, line: usize) -> Option<usize> {
+        data::block_at_line(&self.source_lines, line)
+    }
+}
+
+// The last rendered block list per file, keyed by relative path
+#[derive(Clone, Default)]
+pub struct RenderCache {
+    files: Arc<RwLock<HashMap<String, RenderedFile>>>,
+}
+
+impl RenderCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, relative: &str) -> Option<RenderedFile> {
+        self.files.read().unwrap().get(relative).cloned()
+    }
+
+    // Stores a render unless the file is already cached; returns what is cached
+    pub fn get_or_insert(&self, relative: &str, blocks: Vec<String>, source_lines: Vec<usize>) -> RenderedFile {
+        self.files
+            .write()
+            .unwrap()
+            .entry(relative.to_string())
+            .or_insert(RenderedFile {
+                version: 1,
+                blocks,
+                source_lines,
+            })
+            .clone()
+    }
+
+    // Stores a new render and returns how clients get from the old one to it
+    pub fn update(&self, relative: &str, blocks: Vec<String>, source_lines: Vec<usize>) -> RenderChange {
+        let mut files = self.files.write().unwrap();
+        let Some(cached) = files.get_mut(relative) else {
+            files.insert(
+                relative.to_string(),
+                RenderedFile {
+                    version: 1,
+                    blocks: blocks.clone(),
+                    source_lines,
+                },
+            );
+            return RenderChange::Full { version: 1, blocks };
+        };
+
+        // blank lines move blocks around in the source without changing the render
+        cached.source_lines = source_lines;
+        let ops = diff_blocks(&cached.blocks, &blocks);
+        if ops.is_empty() {
+            return RenderChange::Unchanged;
+        }
+        let base_version = cached.version;
+        cached.version += 1;
+        let version = cached.version;
+        let full_size: usize = blocks.iter().map(String::len).sum();
+        cached.blocks = blocks;
+
+        if patch_size(&ops) >= full_size {
+            RenderChange::Full {
+                version,
+                blocks: cached.blocks.clone(),
+            }
+        } else {
+            RenderChange::Patch {
+                version,
+                base_version,
+                ops,
+            }
+        }
+    }
+
+    pub fn remove(&self, relative: &str) {
+        self.files.write().unwrap().remove(relative);
+    }
+
+    // Moves a file's render, version included, so patches keep applying
+    pub fn rename(&self, from: &str, to: &str) {
+        let mut files = self.files.write().unwrap();
+        if let Some(rendered) = files.remove(from) {
+            files.insert(to.to_string(), rendered);
+        }
+    }
+}
+
+// Keeps the common prefix and suffix and rewrites what's in between. Edits
+// usually touch one place in a file, which this turns into a few ops.
+pub fn diff_blocks(old: &[String], new: &[String]) -> Vec<BlockOp> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut ops = Vec::new();
+    for (offset, (old_block, new_block)) in old_middle.iter().zip(new_middle).enumerate() {
+        if old_block != new_block {
+            ops.push(BlockOp::Replace {
+                index: prefix + offset,
+                html: new_block.clone(),
+            });
+        }
+    }
+    let common = old_middle.len().min(new_middle.len());
+    for (offset, new_block) in new_middle.iter().enumerate().skip(common) {
+        ops.push(BlockOp::Insert {
+            index: prefix + offset,
+            html: new_block.clone(),
+        });
+    }
+    for _ in common..old_middle.len() {
+        ops.push(BlockOp::Delete { index: prefix + common });
+    }
+    ops
+}
+
+// Wraps every block in an element of its own so a client can address blocks
+// by index when applying ops
+pub fn blocks_to_html(blocks: &[String]) -> String {
+    blocks
+        .iter()
+        .map(|block| format!("<div data-block>{}</div>", block))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn patch_size(ops: &[BlockOp]) -> usize {
+    ops.iter()
+        .map(|op| match op {
+            BlockOp::Insert { html, .. } | BlockOp::Replace { html, .. } => html.len() + 32,
+            BlockOp::Delete { .. } => 24,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(text: &str) -> Vec<String> {
+        text.chars().map(String::from).collect()
+    }
+
+    // What a client does with the ops
+    fn apply(old: &[String], ops: &[BlockOp]) -> Vec<String> {
+        let mut blocks = old.to_vec();
+        for op in ops {
+            match op {
+                BlockOp::Insert { index, html } => blocks.insert(*index, html.clone()),
+                BlockOp::Replace { index, html } => blocks[*index] = html.clone(),
+                BlockOp::Delete { index } => {
+                    blocks.remove(*index);
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn diffs_touch_only_the_middle() {
+        assert!(diff_blocks(&blocks("abc"), &blocks("abc")).is_empty());
+        assert_eq!(
+            diff_blocks(&blocks("abcd"), &blocks("axcd")),
+            [BlockOp::Replace {
+                index: 1,
+                html: "x".to_string()
+            }]
+        );
+        assert_eq!(
+            diff_blocks(&blocks("ad"), &blocks("abcd")),
+            [
+                BlockOp::Insert {
+                    index: 1,
+                    html: "b".to_string()
+                },
+                BlockOp::Insert {
+                    index: 2,
+                    html: "c".to_string()
+                }
+            ]
+        );
+        assert_eq!(
+            diff_blocks(&blocks("abcd"), &blocks("ad")),
+            [BlockOp::Delete { index: 1 }, BlockOp::Delete { index: 1 }]
+        );
+    }
+
+    #[test]
+    fn applying_a_diff_gives_the_new_blocks() {
+        let texts = ["", "a", "ab", "abc", "ba", "abab", "xyz", "aabbcc", "cba", "abcabc"];
+        for old in texts {
+            for new in texts {
+                let (old, new) = (blocks(old), blocks(new));
+                assert_eq!(apply(&old, &diff_blocks(&old, &new)), new, "{:?} -> {:?}", old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn updates_are_versioned_and_small_ones_patched() {
+        let cache = RenderCache::new();
+        let long = "x".repeat(100);
+        let first = vec![long.clone(), long.clone(), "a".to_string()];
+        assert!(matches!(cache.update("a.md", first.clone(), vec![1, 3, 5]), RenderChange::Full { version: 1, .. }));
+        assert!(matches!(cache.update("a.md", first.clone(), vec![1, 4, 7]), RenderChange::Unchanged));
+        // the source lines are kept even when the render is unchanged
+        assert_eq!(cache.get("a.md").unwrap().source_lines, [1, 4, 7]);
+
+        let second = vec![long.clone(), long.clone(), "b".to_string()];
+        let RenderChange::Patch {
+            version,
+            base_version,
+            ops,
+        } = cache.update("a.md", second, vec![1, 4, 7])
+        else {
+            panic!("expected a patch");
+        };
+        assert_eq!((version, base_version), (2, 1));
+        assert_eq!(
+            ops,
+            [BlockOp::Replace {
+                index: 2,
+                html: "b".to_string()
+            }]
+        );
+
+        // replacing everything costs more than sending it whole
+        let third = vec!["c".to_string()];
+        assert!(matches!(cache.update("a.md", third, vec![1]), RenderChange::Full { version: 3, .. }));
+    }
+
+    #[test]
+    fn renames_keep_the_version() {
+        let cache = RenderCache::new();
+        cache.update("a.md", blocks("ab"), Vec::new());
+        cache.update("a.md", blocks("abc"), Vec::new());
+        cache.rename("a.md", "b.md");
+        assert!(cache.get("a.md").is_none());
+        assert_eq!(cache.get("b.md").unwrap().version, 2);
+        // a file that is already cached keeps its render
+        assert_eq!(cache.get_or_insert("b.md", blocks("x"), Vec::new()).blocks, blocks("abc"));
+        cache.remove("b.md");
+        assert!(cache.get("b.md").is_none());
+    }
+
+    #[test]
+    fn ops_are_tagged_on_the_wire() {
+        let op = BlockOp::Insert {
+            index: 3,
+            html: "<p>x</p>".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&op).unwrap(),
+            r#"{"op":"insert","index":3,"html":"<p>x</p>"}"#
+        );
+        assert_eq!(serde_json::to_string(&BlockOp::Delete { index: 0 }).unwrap(), r#"{"op":"delete","index":0}"#);
+    }
+}
@@ -0,0 +1,100 @@
+// Who is connected and which file each client is looking at. Every
+// connection registers here on connect and leaves on disconnect; the
+// changes are announced to all clients as client_joined, client_updated and
+// client_left events.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Connection ids start at 1; 0 stands for the file on disk in collab events
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub client_id: u64,
+    // set by the client, e.g. in its hello
+    pub name: Option<String>,
+    // relative path of the file the client is showing, if any
+    pub viewing: Option<String>,
+    // seconds since the Unix epoch
+    pub connected_at: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct Presence {
+    clients: Arc<RwLock<BTreeMap<u64, ClientInfo>>>,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registers a new connection under a fresh id
+    pub fn connect(&self) -> ClientInfo {
+        let client = ClientInfo {
+            client_id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            name: None,
+            viewing: None,
+            connected_at: unix_now(),
+        };
+        self.clients.write().unwrap().insert(client.client_id, client.clone());
+        client
+    }
+
+    pub fn disconnect(&self, client_id: u64) -> bool {
+        self.clients.write().unwrap().remove(&client_id).is_some()
+    }
+
+    pub fn get(&self, client_id: u64) -> Option<ClientInfo> {
+        self.clients.read().unwrap().get(&client_id).cloned()
+    }
+
+    // Each setter returns the updated client, or None if nothing changed
+    pub fn set_name(&self, client_id: u64, name: Option<String>) -> Option<ClientInfo> {
+        self.update(client_id, |client| {
+            if client.name == name {
+                return false;
+            }
+            client.name = name;
+            true
+        })
+    }
+
+    pub fn set_viewing(&self, client_id: u64, viewing: Option<String>) -> Option<ClientInfo> {
+        self.update(client_id, |client| {
+            if client.viewing == viewing {
+                return false;
+            }
+            client.viewing = viewing;
+            true
+        })
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn update(&self, client_id: u64, change: impl FnOnce(&mut ClientInfo) -> bool) -> Option<ClientInfo> {
+        let mut clients = self.clients.write().unwrap();
+        let client = clients.get_mut(&client_id)?;
+        change(client).then(|| client.clone())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
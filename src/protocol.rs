@@ -0,0 +1,378 @@
+// The /ws message protocol. Every message is a JSON object with a `type`
+// tag. Requests carry a client-chosen `id` which the matching response
+// echoes back; push events have no id. The JSON schema in
+// static/protocol.schema.json describes the same messages and must be
+// updated together with this file.
+use crate::collab::Peer;
+use crate::events::UpdateEvent;
+use crate::file_index::FileTreeNode;
+use crate::ot::TextOperation;
+use crate::patch::BlockOp;
+use crate::presence::ClientInfo;
+use crate::root::RootInfo;
+use serde::{Deserialize, Serialize};
+
+// Bumped on incompatible changes to the messages below
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Features a client can rely on, announced in the handshake
+pub const CAPABILITIES: &[&str] = &[
+    "subscribe",
+    "render",
+    "list_files",
+    "get_outline",
+    "file_tree",
+    "content_patch",
+    "scroll_sync",
+    "edit",
+    "collab",
+    "presence",
+    "roots",
+];
+
+pub const SCHEMA: &str = include_str!("../static/protocol.schema.json");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // optional: the server greets every client on connect anyway
+    Hello {
+        id: Option<u64>,
+        protocol_version: u32,
+        client: Option<String>,
+        // display name shown to other clients
+        name: Option<String>,
+    },
+    // path is relative to the watched root, or a glob such as `docs/**/*.md`
+    Subscribe {
+        id: Option<u64>,
+        path: String,
+    },
+    Unsubscribe {
+        id: Option<u64>,
+        path: String,
+    },
+    Render {
+        id: Option<u64>,
+        content: String,
+    },
+    ListFiles {
+        id: Option<u64>,
+    },
+    GetOutline {
+        id: Option<u64>,
+        path: String,
+    },
+    GetFileTree {
+        id: Option<u64>,
+    },
+    // the whole current render, e.g. after a patch that didn't apply
+    GetContent {
+        id: Option<u64>,
+        path: String,
+    },
+    // markdown source of a file, for editing
+    GetSource {
+        id: Option<u64>,
+        path: String,
+    },
+    // base_hash is the hash from the source reply, or null for a new file
+    Save {
+        id: Option<u64>,
+        path: String,
+        content: String,
+        base_hash: Option<String>,
+    },
+    // start editing a file together with others; answered by edit_joined
+    JoinEdit {
+        id: Option<u64>,
+        path: String,
+        // shown to the other collaborators
+        name: Option<String>,
+    },
+    LeaveEdit {
+        id: Option<u64>,
+        path: String,
+    },
+    // an ot.js style operation on the text at `revision`; answered by
+    // edit_ack once applied
+    Edit {
+        path: String,
+        revision: u64,
+        operation: TextOperation,
+    },
+    // cursor or selection in the text at `revision`
+    Selection {
+        path: String,
+        revision: u64,
+        anchor: usize,
+        head: usize,
+    },
+    // the file the client is showing, or null for none; announced to
+    // everyone as client_updated
+    View {
+        id: Option<u64>,
+        path: Option<String>,
+    },
+    ListClients {
+        id: Option<u64>,
+    },
+    // sent by editors as the cursor moves; no reply, previews of the file
+    // get a scroll_to
+    Cursor {
+        path: String,
+        // 1-based source line
+        line: usize,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        protocol_version: u32,
+        server_version: String,
+        capabilities: Vec<String>,
+        // this connection, as it appears in presence events
+        client_id: u64,
+    },
+    // generic success reply for requests without a payload
+    Ack {
+        id: Option<u64>,
+    },
+    RenderResult {
+        id: Option<u64>,
+        html: String,
+    },
+    FileList {
+        id: Option<u64>,
+        files: Vec<String>,
+    },
+    Outline {
+        id: Option<u64>,
+        path: String,
+        headings: Vec<OutlineHeading>,
+    },
+    FileTree {
+        id: Option<u64>,
+        tree: Vec<FileTreeNode>,
+    },
+    Source {
+        id: Option<u64>,
+        path: String,
+        content: String,
+        hash: String,
+    },
+    Saved {
+        id: Option<u64>,
+        path: String,
+        hash: String,
+    },
+    // a save was refused because the file changed on disk in the meantime
+    Conflict {
+        id: Option<u64>,
+        path: String,
+        // what the file is now, None if it was deleted
+        hash: Option<String>,
+        message: String,
+    },
+    EditJoined {
+        id: Option<u64>,
+        path: String,
+        // this connection's id, as seen in other clients' peer messages
+        client_id: u64,
+        revision: u64,
+        content: String,
+        peers: Vec<Peer>,
+    },
+    // the client's own operation was applied and produced `revision`
+    EditAck {
+        path: String,
+        revision: u64,
+    },
+    // someone else's operation, already transformed; produced `revision`.
+    // client_id 0 means the file was changed on disk.
+    RemoteEdit {
+        path: String,
+        client_id: u64,
+        revision: u64,
+        operation: TextOperation,
+    },
+    // a collaborator joined or moved their cursor, in the text at the last
+    // revision the client has received
+    PeerUpdate {
+        path: String,
+        peer: Peer,
+    },
+    PeerLeft {
+        path: String,
+        client_id: u64,
+    },
+    ClientList {
+        id: Option<u64>,
+        clients: Vec<ClientInfo>,
+    },
+    // presence push events, sent to every client
+    ClientJoined {
+        client: ClientInfo,
+    },
+    ClientUpdated {
+        client: ClientInfo,
+    },
+    ClientLeft {
+        client_id: u64,
+    },
+    // the client fell behind and missed events; the current state of what it
+    // follows comes next, as content_update, edit_joined and client_list
+    Resync {
+        reason: String,
+    },
+    // pushed on changes, and the reply to get_content (with its id)
+    ContentUpdate {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        path: String,
+        html: String,
+        version: u64,
+    },
+    // push events
+    ContentPatch {
+        path: String,
+        version: u64,
+        base_version: u64,
+        ops: Vec<BlockOp>,
+    },
+    FileCreated {
+        path: String,
+    },
+    FileDeleted {
+        path: String,
+    },
+    FileRenamed {
+        old_path: String,
+        path: String,
+    },
+    RootAdded {
+        root: RootInfo,
+    },
+    RootRemoved {
+        name: String,
+    },
+    // scroll the preview to block number `block` (counting from 0), which
+    // holds source line `line`
+    ScrollTo {
+        path: String,
+        line: usize,
+        block: usize,
+    },
+    // either a failed request (id set) or a push error (path set when it
+    // concerns a single file)
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutlineHeading {
+    pub level: u8,
+    pub text: String,
+    // the id the heading gets with `heading_ids` enabled
+    pub anchor: String,
+}
+
+impl ServerMessage {
+    pub fn hello(id: Option<u64>, client_id: u64) -> Self {
+        ServerMessage::Hello {
+            id,
+            protocol_version: PROTOCOL_VERSION,
+            server_version: SERVER_VERSION.to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            client_id,
+        }
+    }
+
+    pub fn error(id: Option<u64>, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            id,
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+impl From<UpdateEvent> for ServerMessage {
+    fn from(event: UpdateEvent) -> Self {
+        match event {
+            UpdateEvent::ContentUpdate {
+                file_path,
+                html,
+                version,
+            } => ServerMessage::ContentUpdate {
+                id: None,
+                path: file_path,
+                html,
+                version,
+            },
+            UpdateEvent::ContentPatch {
+                file_path,
+                version,
+                base_version,
+                ops,
+            } => ServerMessage::ContentPatch {
+                path: file_path,
+                version,
+                base_version,
+                ops,
+            },
+            UpdateEvent::FileCreated { file_path } => ServerMessage::FileCreated { path: file_path },
+            UpdateEvent::FileDeleted { file_path } => ServerMessage::FileDeleted { path: file_path },
+            UpdateEvent::FileRenamed { old_path, file_path } => ServerMessage::FileRenamed {
+                old_path,
+                path: file_path,
+            },
+            UpdateEvent::RootAdded { root } => ServerMessage::RootAdded { root },
+            UpdateEvent::RootRemoved { name } => ServerMessage::RootRemoved { name },
+            UpdateEvent::CollabEdit {
+                file_path,
+                origin,
+                revision,
+                operation,
+            } => ServerMessage::RemoteEdit {
+                path: file_path,
+                client_id: origin,
+                revision,
+                operation,
+            },
+            UpdateEvent::PeerUpdate { file_path, peer } => ServerMessage::PeerUpdate { path: file_path, peer },
+            UpdateEvent::PeerLeft { file_path, client_id } => ServerMessage::PeerLeft {
+                path: file_path,
+                client_id,
+            },
+            UpdateEvent::ClientJoined { client } => ServerMessage::ClientJoined { client },
+            UpdateEvent::ClientUpdated { client } => ServerMessage::ClientUpdated { client },
+            UpdateEvent::ClientLeft { client_id } => ServerMessage::ClientLeft { client_id },
+            UpdateEvent::ScrollTo { file_path, line, block } => ServerMessage::ScrollTo {
+                path: file_path,
+                line,
+                block,
+            },
+            UpdateEvent::Error { file_path, message } => ServerMessage::Error {
+                id: None,
+                path: file_path,
+                message,
+            },
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// Parsing and rendering off the async executor. Jobs run on tokio's blocking
+// threads, at most `workers` at a time, so a large file neither stalls the
+// executor nor holds up smaller files while a worker is free. A job cancelled
+// while it waits for a worker never runs; one cancelled while running is
+// left to finish, but its result is dropped.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+#[derive(Clone)]
+pub struct RenderPool {
+    permits: Arc<Semaphore>,
+    workers: usize,
+}
+
+// Held by a job and by whatever may supersede it
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl RenderPool {
+    // 0 is one worker per CPU, but at least two, so one big file never
+    // leaves the others waiting
+    pub fn new(workers: usize) -> Self {
+        let workers = match workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()).max(2),
+            n => n,
+        };
+        Self {
+            permits: Arc::new(Semaphore::new(workers)),
+            workers,
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    // None if the job was cancelled, or panicked
+    pub async fn run<T, F>(&self, cancel: &Cancel, job: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let _permit = self.permits.acquire().await.ok()?;
+        if cancel.is_cancelled() {
+            return None;
+        }
+        let result = tokio::task::spawn_blocking(job).await.ok()?;
+        (!cancel.is_cancelled()).then_some(result)
+    }
+}
@@ -1,45 +1,230 @@
-use crate::data::{Block, Document, Element};
+use crate::data::{plain_text, Alignment, Block, Document, Element};
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct RenderOptions {
+    // add slug ids to headings so sections can be linked to
+    pub heading_ids: bool,
+    // add data-line="N" to each block with the source line it starts on
+    pub source_lines: bool,
+}
+
+// Built-in page used by `build` when no template is configured
+pub const DEFAULT_PAGE_TEMPLATE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="UTF-8" />
+<title>{{title}}</title>
+</head>
+<body data-theme="{{theme}}">
+<article class="markdown-body">
+{{content}}
+</article>
+</body>
+</html>
+"#;
 
 // Renders to render element, list of elements, block and whole document
 impl Document {
     pub fn to_html(&self) -> String {
+        self.to_html_with(&RenderOptions::default())
+    }
+
+    pub fn to_html_with(&self, options: &RenderOptions) -> String {
+        self.render_blocks(options).join("\n")
+    }
+
+    // HTML of each block on its own, in document order
+    pub fn render_blocks(&self, options: &RenderOptions) -> Vec<String> {
         self.blocks
             .iter()
-            .map(|block| render_block(block))
-            .collect::<Vec<String>>()
-            .join("\n")
+            .enumerate()
+            .map(|(index, block)| {
+                let html = render_block(block, options);
+                match self.source_lines.get(index) {
+                    Some(line) if options.source_lines => with_source_line(html, *line),
+                    _ => html,
+                }
+            })
+            .collect()
     }
 }
 
-fn render_block(block: &Block) -> String {
+// Fills the {{title}}, {{theme}} and {{content}} placeholders of a page
+// template. title and theme are plain text and escaped here; content is HTML.
+pub fn render_page(template: &str, title: &str, theme: &str, content: &str) -> String {
+    // content goes last so placeholders inside the document are left alone
+    template
+        .replace("{{title}}", &escape_html(title))
+        .replace("{{theme}}", &escape_html(theme))
+        .replace("{{content}}", content)
+}
+
+// Puts the attribute on the block's opening tag; raw HTML that doesn't start
+// with an element is left alone
+fn with_source_line(mut html: String, line: usize) -> String {
+    if !html.starts_with('<') || !html[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return html;
+    }
+    let name_end = html[1..]
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .map_or(html.len(), |end| end + 1);
+    html.insert_str(name_end, &format!(" data-line=\"{}\"", line));
+    html
+}
+
+fn render_block(block: &Block, options: &RenderOptions) -> String {
     match block {
         Block::Title { level, content } => {
             let content_html = render_elements(content);
-            format!("<h{}>{}</h{}>", level, content_html, level)
+            if options.heading_ids {
+                format!(
+                    "<h{} id=\"{}\">{}</h{}>",
+                    level,
+                    slugify(&plain_text(content)),
+                    content_html,
+                    level
+                )
+            } else {
+                format!("<h{}>{}</h{}>", level, content_html, level)
+            }
         }
         Block::Paragraph(element) => {
             let content_html = render_elements(element);
             format!("<p>{}</p>", content_html)
         }
-        Block::CodeBlock(code) => {
-            format!("<pre><code>{}</pre><code>", code)
+        Block::CodeBlock { language, code } => match language {
+            Some(language) => format!(
+                "<pre><code class=\"language-{}\">{}</code></pre>",
+                escape_html(language),
+                escape_html(code)
+            ),
+            None => format!("<pre><code>{}</code></pre>", escape_html(code)),
+        },
+        Block::List { start, items } => {
+            let items_html: String = items
+                .iter()
+                .map(|item| match item.checked {
+                    Some(checked) => format!(
+                        "<li class=\"task-list-item\"><input type=\"checkbox\" disabled{} /> {}</li>",
+                        if checked { " checked" } else { "" },
+                        render_elements(&item.content)
+                    ),
+                    None => format!("<li>{}</li>", render_elements(&item.content)),
+                })
+                .collect();
+            match start {
+                Some(1) => format!("<ol>{}</ol>", items_html),
+                Some(n) => format!("<ol start=\"{}\">{}</ol>", n, items_html),
+                None => format!("<ul>{}</ul>", items_html),
+            }
+        }
+        Block::Table {
+            alignments,
+            header,
+            rows,
+        } => {
+            let header_html = render_row("th", header, alignments);
+            let rows_html: String = rows.iter().map(|row| render_row("td", row, alignments)).collect();
+            format!(
+                "<table><thead>{}</thead><tbody>{}</tbody></table>",
+                header_html, rows_html
+            )
         }
+        Block::Math(math) => format!("<div class=\"math math-display\">\\[{}\\]</div>", escape_html(math)),
+        Block::Html(html) => html.clone(),
+        // not expanded, e.g. parsed from text without a file: shown as written
+        Block::Include(path) => format!("<p>{{{{include {}}}}}</p>", escape_html(path)),
+        Block::Footnote { label, content } => {
+            let label = escape_html(label);
+            format!(
+                "<div class=\"footnote\" id=\"fn-{}\"><sup>{}</sup> {} <a href=\"#fnref-{}\">&#8617;</a></div>",
+                label,
+                label,
+                render_elements(content),
+                label
+            )
+        }
+        Block::BlockQuote(blocks) => {
+            let inner: String = blocks
+                .iter()
+                .map(|block| format!("{}\n", render_block(block, options)))
+                .collect();
+            format!("<blockquote>\n{}</blockquote>", inner)
+        }
+        Block::Rule => "<hr />".to_string(),
     }
 }
 
-fn render_elements(elements: &Vec<Element>) -> String {
+fn render_row(tag: &str, cells: &[Vec<Element>], alignments: &[Alignment]) -> String {
+    let cells_html: String = cells
+        .iter()
+        .zip(alignments)
+        .map(|(cell, alignment)| {
+            let style = match alignment {
+                Alignment::None => "",
+                Alignment::Left => " style=\"text-align: left\"",
+                Alignment::Center => " style=\"text-align: center\"",
+                Alignment::Right => " style=\"text-align: right\"",
+            };
+            format!("<{}{}>{}</{}>", tag, style, render_elements(cell), tag)
+        })
+        .collect();
+    format!("<tr>{}</tr>", cells_html)
+}
+
+fn render_elements(elements: &[Element]) -> String {
     elements
         .iter()
-        .map(|element| render_element(element))
+        .map(render_element)
         .collect::<Vec<String>>()
         .join("")
 }
 
 fn render_element(element: &Element) -> String {
     match element {
-        Element::PlainText(text) => text.clone(),
-        Element::Bold(text) => format!("<strong>{}</strong>", text),
-        Element::Italic(text) => format!("<em>{}</em>", text),
+        Element::PlainText(text) => escape_html(text),
+        Element::Bold(text) => format!("<strong>{}</strong>", escape_html(text)),
+        Element::Italic(text) => format!("<em>{}</em>", escape_html(text)),
+        Element::Code(code) => format!("<code>{}</code>", escape_html(code)),
+        Element::Strikethrough(text) => format!("<del>{}</del>", escape_html(text)),
+        Element::Math(math) => format!("<span class=\"math math-inline\">\\({}\\)</span>", escape_html(math)),
+        Element::Link { text, url } => format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(text)),
+        Element::FootnoteRef(label) => {
+            let label = escape_html(label);
+            format!(
+                "<sup class=\"footnote-ref\"><a href=\"#fn-{}\" id=\"fnref-{}\">{}</a></sup>",
+                label, label, label
+            )
+        }
+        Element::Html(html) => html.clone(),
     }
 }
 
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// "Getting Started!" -> "getting-started"
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
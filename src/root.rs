@@ -0,0 +1,168 @@
+// A watched directory, or a single watched file, with its own markdawn.toml.
+// Clients see a root's files under its name ("notes/todo.md", or just
+// "todo.md" for a single file named that); the root a server was started
+// with on its own has an empty name and its files are at the top level, as
+// if it were the only one.
+use crate::config::SharedConfig;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone)]
+pub struct Root {
+    pub name: String,
+    // the directory that is watched; a single file's parent
+    pub dir: PathBuf,
+    // in single-file mode, the only file the root has
+    pub file: Option<PathBuf>,
+    pub config: SharedConfig,
+}
+
+// A root as the HTTP API shows it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootInfo {
+    pub name: String,
+    pub path: String,
+    pub single_file: bool,
+}
+
+impl Root {
+    // path is canonicalized; config is the one loaded for it
+    pub fn new(path: &Path, name: &str, config: SharedConfig) -> Result<Self, String> {
+        validate_name(name)?;
+        let path = path
+            .canonicalize()
+            .map_err(|e| format!("Failed to resolve {}: {}", path.display(), e))?;
+        if !path.is_file() {
+            return Ok(Self {
+                name: name.to_string(),
+                dir: path,
+                file: None,
+                config,
+            });
+        }
+
+        if !config.get().watch.file_types().is_document(&path) {
+            return Err(format!("Not a markdown or text file: {}", path.display()));
+        }
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_else(|| path.clone());
+        Ok(Self {
+            name: name.to_string(),
+            dir,
+            file: Some(path),
+            config,
+        })
+    }
+
+    pub fn info(&self) -> RootInfo {
+        RootInfo {
+            name: self.name.clone(),
+            path: self.file.as_ref().unwrap_or(&self.dir).display().to_string(),
+            single_file: self.file.is_some(),
+        }
+    }
+
+    // Whether a path on disk is in this root. In single-file mode only the
+    // file itself is.
+    pub fn contains(&self, path: &Path) -> bool {
+        match &self.file {
+            Some(file) => path == file,
+            None => path.starts_with(&self.dir),
+        }
+    }
+
+    // Two roots can't share files, or events for them would be handled twice
+    pub fn overlaps(&self, other: &Root) -> bool {
+        match (&self.file, &other.file) {
+            (Some(file), Some(other_file)) => file == other_file,
+            (Some(file), None) => other.contains(file),
+            (None, Some(other_file)) => self.contains(other_file),
+            (None, None) => self.dir.starts_with(&other.dir) || other.dir.starts_with(&self.dir),
+        }
+    }
+
+    // "/abs/dir/docs/a.md" -> "<name>/docs/a.md", or "docs/a.md" for the
+    // top-level root. A single file is just its name.
+    pub fn client_path(&self, path: &Path) -> String {
+        if self.file.as_deref() == Some(path) && !self.name.is_empty() {
+            return self.name.clone();
+        }
+        let relative = match path.strip_prefix(&self.dir) {
+            Ok(relative) => relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            Err(_) => return path.to_string_lossy().to_string(),
+        };
+        if self.name.is_empty() {
+            relative
+        } else {
+            format!("{}/{}", self.name, relative)
+        }
+    }
+
+    // A client path with the root's name already taken off, refusing
+    // anything outside the root
+    pub fn resolve(&self, relative: &str) -> Result<PathBuf, String> {
+        if let Some(file) = &self.file
+            && !self.name.is_empty()
+        {
+            return match relative.trim_start_matches('/') {
+                "" => Ok(file.clone()),
+                _ => Err(format!("Not found: {}/{}", self.name, relative)),
+            };
+        }
+        let relative = Path::new(relative.trim_start_matches('/'));
+        let mut path = self.dir.clone();
+        for component in relative.components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::CurDir => {}
+                _ => return Err(format!("Invalid path: {}", relative.display())),
+            }
+        }
+        if self.file.as_ref().is_some_and(|file| *file != path) {
+            return Err(format!("Not found: {}", relative.display()));
+        }
+        Ok(path)
+    }
+
+    // Whether a file, existing or not, would be one of the root's documents:
+    // of a document type, and not excluded by the filter or ignore files
+    pub fn admits(&self, path: &Path) -> bool {
+        if let Some(file) = &self.file {
+            return path == file;
+        }
+        let Ok(relative) = path.strip_prefix(&self.dir) else {
+            return false;
+        };
+        let watch = self.config.get().watch;
+        watch.file_types().is_document(path) && watch.filter_for(&self.dir).unwrap_or_default().matches(relative)
+    }
+
+    // The root's documents that pass its filter
+    pub fn documents(&self) -> Vec<PathBuf> {
+        if let Some(file) = &self.file {
+            return if file.is_file() { vec![file.clone()] } else { Vec::new() };
+        }
+        let watch = self.config.get().watch;
+        let filter = watch.filter_for(&self.dir).unwrap_or_default();
+        crate::watcher::find_markdown_files(&self.dir, &filter, &watch.file_types()).unwrap_or_default()
+    }
+}
+
+// The name a root gets when none is given: its directory's or file's name
+pub fn default_name(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "root".to_string())
+}
+
+// Names are a single path segment, so they can't be confused with a path
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.contains('/') || name.contains('\\') || name == "." || name == ".." {
+        return Err(format!("Invalid root name '{}'", name));
+    }
+    Ok(())
+}
@@ -0,0 +1,377 @@
+use crate::config::SharedConfig;
+use crate::data::Document;
+use crate::events::UpdateEvent;
+use crate::file_index::FileTreeNode;
+use crate::patch::blocks_to_html;
+use crate::presence::ClientInfo;
+use crate::protocol;
+use crate::renderer::{self, escape_html};
+use crate::root::{self, Root, RootInfo};
+use crate::sse;
+use crate::watcher::Watchers;
+use crate::websocket::{self, ConnectionManager};
+use crate::workspace::{SaveError, Workspace};
+use axum::{
+    Json, Router,
+    extract::{FromRef, Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tower_http::services::ServeDir;
+
+// How long clients get to receive their last events and close
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Shared by all HTTP handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub connection_manager: ConnectionManager,
+    pub workspace: Workspace,
+    pub watchers: Watchers,
+}
+
+impl FromRef<AppState> for ConnectionManager {
+    fn from_ref(state: &AppState) -> Self {
+        state.connection_manager.clone()
+    }
+}
+
+impl FromRef<AppState> for Workspace {
+    fn from_ref(state: &AppState) -> Self {
+        state.workspace.clone()
+    }
+}
+
+#[derive(Deserialize)]
+struct MarkdownRequest {
+    content: String,
+    // add data-line attributes even if the config doesn't, for scroll sync
+    #[serde(default)]
+    source_lines: bool,
+}
+
+#[derive(Serialize)]
+struct SourceResponse {
+    path: String,
+    content: String,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct SaveRequest {
+    content: String,
+    // hash from GET /api/files/<path>; null or missing to create the file
+    #[serde(default)]
+    base_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SaveResponse {
+    path: String,
+    // the saved content's hash, or the current one on a conflict
+    hash: Option<String>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct AddRootRequest {
+    // a directory or a single markdown or text file on the server
+    path: String,
+    // defaults to the directory's or file's name
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    server_version: String,
+    protocol_version: u32,
+    uptime_secs: u64,
+    roots: Vec<String>,
+    files: usize,
+    connections: usize,
+    clients: Vec<ClientInfo>,
+    // seconds since the Unix epoch, null if nothing changed since startup
+    last_event: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct MarkdownResponse {
+    html: String,
+    success: bool,
+    message: String,
+}
+
+pub fn router(state: AppState) -> Router {
+    let static_dir = state.workspace.config().get().server.static_dir;
+
+    Router::new()
+        .route("/", get(serve_index))
+        .route("/demo", get(serve_demo))
+        .route("/api/convert", post(convert_markdown))
+        .route("/api/protocol.schema.json", get(serve_protocol_schema))
+        .route("/api/status", get(status))
+        .route("/api/files", get(list_files))
+        .route("/api/files/{*path}", get(get_source).put(save_file))
+        .route("/api/roots", get(list_roots).post(add_root))
+        .route("/api/roots/{name}", delete(remove_root))
+        .route("/view/{*path}", get(view_file))
+        // WebSocket endpoint for real-time updates
+        .route("/ws", get(websocket::websocket_handler))
+        // the same push events over Server-Sent Events
+        .route("/events", get(sse::sse_handler))
+        // Pass connection_manager and workspace as application state
+        .with_state(state)
+        .nest_service("/static", ServeDir::new(static_dir))
+}
+
+// Serves until `shutdown` resolves, then shuts down gracefully: the
+// watchers render what they still had pending, editing sessions are saved,
+// and every client gets the resulting events before its connection closes
+pub async fn serve(listener: TcpListener, state: AppState, shutdown: impl Future<Output = ()> + Send + 'static) -> std::io::Result<()> {
+    let watchers = state.watchers.clone();
+    let workspace = state.workspace.clone();
+    let connection_manager = state.connection_manager.clone();
+    let stopping = async move {
+        shutdown.await;
+        println!("Shutting down");
+        watchers.stop_all().await;
+        workspace.collab().flush(&workspace).await;
+        connection_manager.shutdown();
+    };
+    axum::serve(listener, router(state.clone()))
+        .with_graceful_shutdown(stopping)
+        .await?;
+
+    // axum doesn't wait for upgraded WebSocket connections
+    if !state.connection_manager.wait_closed(SHUTDOWN_TIMEOUT).await {
+        println!("Some clients didn't close in time");
+    }
+    Ok(())
+}
+
+async fn serve_index(State(state): State<AppState>) -> Html<String> {
+    let theme = state.workspace.config().get().ui.theme;
+    Html(include_str!("../static/index.html").replace("<body>", &format!("<body data-theme=\"{}\">", theme)))
+}
+
+async fn serve_protocol_schema() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/schema+json")], protocol::SCHEMA)
+}
+
+async fn status(State(state): State<AppState>) -> Json<StatusResponse> {
+    let connection_manager = &state.connection_manager;
+    let clients = connection_manager.presence.clients();
+    Json(StatusResponse {
+        server_version: protocol::SERVER_VERSION.to_string(),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        uptime_secs: connection_manager.started_at.elapsed().as_secs(),
+        roots: state.workspace.roots().iter().map(|root| root.info().path).collect(),
+        files: state.workspace.files().len(),
+        connections: clients.len(),
+        clients,
+        last_event: connection_manager.last_event(),
+    })
+}
+
+async fn list_files(State(state): State<AppState>) -> Json<Vec<FileTreeNode>> {
+    Json(state.workspace.files().tree())
+}
+
+async fn list_roots(State(state): State<AppState>) -> Json<Vec<RootInfo>> {
+    Json(state.workspace.roots().iter().map(Root::info).collect())
+}
+
+// Serves another directory or file next to the existing roots, with its own
+// markdawn.toml and the command-line flags the server was started with
+async fn add_root(
+    State(state): State<AppState>,
+    Json(payload): Json<AddRootRequest>,
+) -> Result<(StatusCode, Json<RootInfo>), (StatusCode, String)> {
+    let path = std::path::Path::new(&payload.path);
+    let name = payload.name.unwrap_or_else(|| root::default_name(path));
+    if name.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Added roots need a name".to_string()));
+    }
+    let overrides = state.workspace.config().overrides().clone();
+    let root = SharedConfig::load(path, overrides)
+        .and_then(|config| Root::new(path, &name, config))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .workspace
+        .add_root(root.clone())
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+
+    state.workspace.scan_root(&root);
+    state.watchers.start(root.clone());
+    println!("Added root {}: {}", root.name, root.info().path);
+    let info = root.info();
+    state
+        .connection_manager
+        .send_update(UpdateEvent::RootAdded { root: info.clone() })
+        .await;
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+// The root the server was started with on its own has no name, and stays
+async fn remove_root(State(state): State<AppState>, Path(name): Path<String>) -> Result<StatusCode, (StatusCode, String)> {
+    if !state.workspace.roots().iter().any(|root| root.name == name) {
+        return Err((StatusCode::NOT_FOUND, format!("No root named '{}'", name)));
+    }
+    // no events for the root's files once it's gone
+    state.watchers.stop(&name).await;
+    let root = state
+        .workspace
+        .remove_root(&name)
+        .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    println!("Removed root {}: {}", root.name, root.info().path);
+    state
+        .connection_manager
+        .send_update(UpdateEvent::RootRemoved { name })
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The hash doubles as the ETag, so a client polling with If-None-Match gets
+// a 304 until the file changes
+async fn get_source(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let source = state
+        .workspace
+        .read_source(&path)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    let etag = etag(&source.hash);
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    let body = Json(SourceResponse {
+        path,
+        content: source.content,
+        hash: source.hash,
+    });
+    Ok(([(header::ETAG, etag)], body).into_response())
+}
+
+fn etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+// If-None-Match may list several tags, or be `*`
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+// 409 with the current hash if the file changed since base_hash was read
+async fn save_file(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Json(payload): Json<SaveRequest>,
+) -> Response {
+    let result = state
+        .workspace
+        .save_file(&path, &payload.content, payload.base_hash.as_deref())
+        .await;
+    let (status, hash, message) = match result {
+        Ok(hash) => (StatusCode::OK, Some(hash), "Saved".to_string()),
+        Err(SaveError::Conflict { current_hash, message }) => (StatusCode::CONFLICT, current_hash, message),
+        Err(SaveError::Invalid(message)) => (StatusCode::BAD_REQUEST, None, message),
+        Err(SaveError::Failed(message)) => (StatusCode::INTERNAL_SERVER_ERROR, None, message),
+    };
+    let etag = hash.as_deref().filter(|_| status == StatusCode::OK).map(etag);
+    let body = Json(SaveResponse { path, hash, message });
+    match etag {
+        Some(etag) => (status, [(header::ETAG, etag)], body).into_response(),
+        None => (status, body).into_response(),
+    }
+}
+
+// A single file as a full page that follows live updates
+async fn view_file(State(state): State<AppState>, Path(path): Path<String>) -> Result<Html<String>, (StatusCode, String)> {
+    // start from the same render the watcher diffs against, so patches apply
+    let rendered = state
+        .workspace
+        .rendered_file(&path)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+
+    let title = state
+        .workspace
+        .files()
+        .get(&path)
+        .and_then(|entry| entry.title)
+        .unwrap_or_else(|| path.clone());
+    let theme = state.workspace.config().get().ui.theme;
+    let template = include_str!("../static/view.html")
+        .replace("{{path}}", &escape_html(&path))
+        .replace("{{version}}", &rendered.version.to_string());
+    Ok(Html(renderer::render_page(
+        &template,
+        &title,
+        &theme,
+        &blocks_to_html(&rendered.blocks),
+    )))
+}
+
+async fn serve_demo(State(state): State<AppState>) -> Html<String> {
+    let sample_doc = create_sample_document();
+    Html(sample_doc.to_html_with(&state.workspace.config().get().render))
+}
+
+fn create_sample_document() -> Document {
+    use crate::data::*;
+    Document {
+        blocks: vec![
+            Block::Title {
+                level: 1,
+                content: vec![Element::PlainText("Sample Document".to_string())],
+            },
+            Block::Paragraph(vec![
+                Element::PlainText("This is a ".to_string()),
+                Element::Bold("bold".to_string()),
+                Element::PlainText(" and ".to_string()),
+                Element::Italic("italic".to_string()),
+                Element::PlainText(" text example.".to_string()),
+            ]),
+        ],
+        front_matter: None,
+        source_lines: Vec::new(),
+    }
+}
+
+async fn convert_markdown(
+    State(state): State<AppState>,
+    Json(payload): Json<MarkdownRequest>,
+) -> Json<MarkdownResponse> {
+    let mut render_options = state.workspace.config().get().render;
+    render_options.source_lines |= payload.source_lines;
+    match state.workspace.parse_text(&payload.content) {
+        Ok(document) => {
+            let html = document.to_html_with(&render_options);
+            Json(MarkdownResponse {
+                html,
+                success: true,
+                message: "Conversion successful".to_string(),
+            })
+        }
+        Err(e) => Json(MarkdownResponse {
+            html: String::new(),
+            success: false,
+            message: format!("Parse error: {}", e),
+        }),
+    }
+}
\ No newline at end of file
@@ -0,0 +1,221 @@
+// Server-Sent Events: the push half of /ws over plain HTTP, for networks
+// where WebSockets don't get through. Each SSE event carries one
+// ServerMessage as JSON, exactly as /ws would send it, and the id of the
+// broadcast event it came from so a reconnecting client can resume where
+// it left off with Last-Event-ID.
+use crate::events::{SequencedEvent, UpdateEvent};
+use crate::protocol::ServerMessage;
+use crate::websocket::{self, ConnectionManager, Subscriptions};
+use crate::workspace::Workspace;
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::stream::{self, Stream};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+// Events waiting for a slow client before the broadcast is held up for it,
+// which eventually makes it lag and get a resync
+const SEND_BUFFER: usize = 16;
+
+// Where a client (re)connecting starts from
+enum Start {
+    Fresh,
+    // the Last-Event-ID it sent
+    After(u64),
+    // an id from an earlier run of the server, whose events are gone
+    Unknown,
+}
+
+// GET /events?path=notes.md&path=docs/*.md&name=alice&view=notes.md
+//
+// `path` subscribes like the /ws subscribe message and may be repeated;
+// without one every event is sent. `name` and `view` set the presence info.
+pub async fn sse_handler(
+    State(connection_manager): State<ConnectionManager>,
+    State(workspace): State<Workspace>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let start = match headers.get("last-event-id").and_then(|value| value.to_str().ok()) {
+        Some(value) => parse_event_id(value, connection_manager.epoch),
+        None => Start::Fresh,
+    };
+    let heartbeat = workspace.config().get().server.heartbeat();
+
+    let (tx, rx) = mpsc::channel(SEND_BUFFER);
+    tokio::spawn(async move {
+        let client_id = connection_manager.connect().client_id;
+        stream_events(&connection_manager, &workspace, client_id, params, start, &tx).await;
+        connection_manager.disconnect(client_id);
+        println!("SSE client {} disconnected", client_id);
+    });
+
+    let events = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (Ok(event), rx)) });
+    Sse::new(events).keep_alive(KeepAlive::new().interval(heartbeat))
+}
+
+// Feeds one SSE client until it disconnects
+async fn stream_events(
+    connection_manager: &ConnectionManager,
+    workspace: &Workspace,
+    client_id: u64,
+    params: Vec<(String, String)>,
+    start: Start,
+    tx: &mpsc::Sender<Event>,
+) {
+    let (mut rx, missed) = match start {
+        Start::After(last_id) => connection_manager.resume(last_id),
+        Start::Fresh | Start::Unknown => (connection_manager.broadcast_tx.subscribe(), None),
+    };
+    let epoch = connection_manager.epoch;
+    if !send(tx, message_event(&ServerMessage::hello(None, client_id))).await {
+        return;
+    }
+
+    let mut subscriptions = Subscriptions::new(client_id);
+    let mut presence_changed = false;
+    for (key, value) in params {
+        let result = match key.as_str() {
+            "path" => subscriptions.add(&value),
+            "name" => {
+                presence_changed |= connection_manager.presence.set_name(client_id, Some(value)).is_some();
+                Ok(())
+            }
+            "view" => workspace.resolve(&value).map(|full_path| {
+                let path = workspace.relative_path(&full_path);
+                presence_changed |= connection_manager.presence.set_viewing(client_id, Some(path)).is_some();
+            }),
+            _ => Ok(()),
+        };
+        if let Err(message) = result
+            && !send(tx, message_event(&ServerMessage::error(None, message))).await
+        {
+            return;
+        }
+    }
+    if presence_changed && let Some(client) = connection_manager.presence.get(client_id) {
+        let _ = connection_manager.broadcast(UpdateEvent::ClientUpdated { client });
+    }
+    let patterns = subscriptions.patterns();
+    let subscriptions = Mutex::new(subscriptions);
+
+    let catch_up = match (start, missed) {
+        // a fresh connection starts from the current render, like subscribe,
+        // and who is connected, which it has no other way to ask for
+        (Start::Fresh, _) => {
+            let mut messages = Vec::new();
+            for pattern in &patterns {
+                messages.extend(websocket::initial_render(pattern, workspace).await);
+            }
+            messages.push(ServerMessage::ClientList {
+                id: None,
+                clients: connection_manager.presence.clients(),
+            });
+            messages.iter().map(message_event).collect()
+        }
+        (_, Some(missed)) => missed
+            .into_iter()
+            .filter(|sequenced| subscriptions.lock().unwrap().wants(&sequenced.event))
+            .map(|sequenced| to_event(sequenced, epoch))
+            .collect(),
+        (_, None) => {
+            let reason = "Missed updates while disconnected".to_string();
+            resync(reason, &subscriptions, workspace, connection_manager).await
+        }
+    };
+    for event in catch_up {
+        if !send(tx, event).await {
+            return;
+        }
+    }
+
+    loop {
+        let received = tokio::select! {
+            received = rx.recv() => received,
+            // there may be nothing to send for a long time; notice a client
+            // that is gone so it leaves the presence list
+            _ = tx.closed() => return,
+            // the server is shutting down: send what was broadcast before,
+            // then end the stream
+            _ = connection_manager.closing() => {
+                while let Ok(sequenced) = rx.try_recv() {
+                    let wanted = subscriptions.lock().unwrap().wants(&sequenced.event);
+                    if wanted && !send(tx, to_event(sequenced, epoch)).await {
+                        return;
+                    }
+                }
+                return;
+            }
+        };
+        let events = match received {
+            Ok(sequenced) if subscriptions.lock().unwrap().wants(&sequenced.event) => vec![to_event(sequenced, epoch)],
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                println!("SSE client {} missed {} updates", client_id, missed);
+                let reason = format!("Missed {} updates", missed);
+                resync(reason, &subscriptions, workspace, connection_manager).await
+            }
+            Err(RecvError::Closed) => return,
+        };
+        for event in events {
+            if !send(tx, event).await {
+                return;
+            }
+        }
+    }
+}
+
+// SSE clients can't edit, so there are no sessions to restart
+async fn resync(
+    reason: String,
+    subscriptions: &Mutex<Subscriptions>,
+    workspace: &Workspace,
+    connection_manager: &ConnectionManager,
+) -> Vec<Option<Event>> {
+    websocket::resync(reason, subscriptions, workspace, connection_manager, &mut HashMap::new())
+        .await
+        .iter()
+        .map(message_event)
+        .collect()
+}
+
+async fn send(tx: &mpsc::Sender<Event>, event: Option<Event>) -> bool {
+    match event {
+        Some(event) => tx.send(event).await.is_ok(),
+        None => true,
+    }
+}
+
+// A broadcast event, with an id to resume from
+fn to_event(sequenced: SequencedEvent, epoch: u64) -> Option<Event> {
+    message_event(&ServerMessage::from(sequenced.event)).map(|event| event.id(format!("{}-{}", epoch, sequenced.id)))
+}
+
+// Messages that aren't broadcast events have no id, so the browser keeps
+// the last one it saw
+fn message_event(message: &ServerMessage) -> Option<Event> {
+    match message.to_json() {
+        Ok(json) => Some(Event::default().data(json)),
+        Err(e) => {
+            println!("Failed to serialize event: {} ", e);
+            None
+        }
+    }
+}
+
+// Ids look like `<epoch>-<n>`
+fn parse_event_id(value: &str, epoch: u64) -> Start {
+    match value.split_once('-') {
+        Some((event_epoch, id)) if event_epoch.parse() == Ok(epoch) => match id.parse() {
+            Ok(id) => Start::After(id),
+            Err(_) => Start::Unknown,
+        },
+        _ => Start::Unknown,
+    }
+}
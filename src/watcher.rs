@@ -1,179 +1,1128 @@
-use crate::events::{FileEvent, UpdateEvent};
-use crate::parser;
+use crate::config::WatchMode;
+use crate::debounce::{self, Debouncer};
+use crate::events::UpdateEvent;
+use crate::file_types::FileTypes;
+use crate::filter::PathFilter;
+use crate::ignore::IGNORE_FILE_NAMES;
+use crate::patch::{blocks_to_html, RenderChange};
+use crate::render_pool::Cancel;
+use crate::root::Root;
 use crate::websocket::ConnectionManager;
-use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use std::collections::HashMap;
+use crate::workspace::{self, Workspace};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Instant};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 
+// Follows one root. A directory root's subdirectories are watched one by
+// one; a single file only has its directory watched, non-recursively, and
+// events for anything else in it are dropped.
 pub struct FileWatcher {
     pub connection_manager: ConnectionManager,
-    pub debounce_duration: Duration,
+    // matched against paths relative to the watched root
+    pub filter: PathFilter,
+    pub file_types: FileTypes,
+    workspace: Workspace,
+    root: Root,
     watched_files: HashMap<PathBuf, FileMetadata>,
+    // paths with events that haven't been processed yet
+    debouncer: Debouncer,
+    // directories with a watch of their own; excluded ones have none
+    watched_dirs: HashSet<PathBuf>,
+    // renames not processed yet, new path -> old path
+    renames: HashMap<PathBuf, PathBuf>,
+    // the first half of a rename reported in two events, and its tracker
+    rename_from: Option<(PathBuf, Option<usize>)>,
+    // events come from a PollWatcher rather than the native backend
+    polling: bool,
+    // the native backend ran out of watches; in auto mode this switches
+    // to polling
+    watch_limit_hit: bool,
+    // at most one per file, the newest; starting another cancels it
+    rendering: HashMap<PathBuf, PendingRender>,
+    next_render: u64,
+    render_tx: mpsc::UnboundedSender<RenderDone>,
+    render_rx: Option<mpsc::UnboundedReceiver<RenderDone>>,
+    // the files each document includes, directly or through other includes;
+    // a change to one of them re-renders the document
+    includes: HashMap<PathBuf, Vec<PathBuf>>,
+    // includes changed, so their directories may need watches
+    includes_changed: bool,
+    // the config file or an ignore file settled after a change; applied by
+    // the watcher loop, once however many events there were
+    reload_pending: bool,
+    refilter_pending: bool,
+}
+
+// Unbounded: notify's watch() waits for its event thread, so a full channel
+// that nobody reads while the watches are being set up would deadlock
+type EventSender = mpsc::UnboundedSender<notify::Result<Event>>;
+
+// A render handed to the pool and not applied yet
+struct PendingRender {
+    id: u64,
+    // None until a render that reads the file itself has read it
+    content_hash: Option<u64>,
+    cancel: Cancel,
+    // only fills the render cache: clients aren't told, and a file that
+    // can't be read or parsed is skipped
+    cache_only: bool,
+}
+
+// Applied by the watcher loop, so index changes and events stay in order
+struct RenderDone {
+    path: PathBuf,
+    id: u64,
+    result: Result<Rendered, String>,
+}
+
+struct Rendered {
+    content_hash: u64,
+    source_hash: String,
+    title: Option<String>,
+    blocks: Vec<String>,
+    source_lines: Vec<usize>,
+    includes: Vec<PathBuf>,
 }
 
 #[derive(Debug)]
 struct FileMetadata {
-    last_modified: Instant,
-    content_hash: u64, // 可以用来检测内容是否真的改变了
+    // of the content last rendered; a write that leaves it the same is skipped
+    content_hash: u64,
 }
 
 impl FileWatcher {
-    pub fn new(connection_manager: ConnectionManager) -> Self {
+    pub fn new(connection_manager: ConnectionManager, workspace: Workspace, root: Root) -> Self {
+        let current = root.config.get();
+        let (render_tx, render_rx) = mpsc::unbounded_channel();
         Self {
             connection_manager,
-            debounce_duration: Duration::from_millis(300),
+            // the config was validated when it was loaded
+            filter: current.watch.filter_for(&root.dir).unwrap_or_default(),
+            file_types: current.watch.file_types(),
+            root,
+            workspace,
             watched_files: HashMap::new(),
+            debouncer: Debouncer::new(current.watch.debounce(), current.watch.max_wait()),
+            watched_dirs: HashSet::new(),
+            renames: HashMap::new(),
+            rename_from: None,
+            polling: false,
+            watch_limit_hit: false,
+            rendering: HashMap::new(),
+            next_render: 0,
+            render_tx,
+            render_rx: Some(render_rx),
+            includes: HashMap::new(),
+            includes_changed: false,
+            reload_pending: false,
+            refilter_pending: false,
         }
     }
 
-    pub async fn start_watching<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn std::error::Error>> {
-        let (tx, mut rx) = mpsc::channel::<Result<Event, notify::Error>>(100);
+    // Runs until `stop` is set (or its sender dropped), then renders what
+    // is still pending so no change made before the stop is lost
+    pub async fn start_watching(&mut self, mut stop: watch::Receiver<bool>) -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+        let mut render_rx = self.render_rx.take().ok_or("Watcher already started")?;
+        let settings = self.root.config.get().watch;
+
+        let mut watcher = match settings.mode {
+            WatchMode::Native => native_watcher(&tx)?,
+            WatchMode::Poll => self.poll_watcher(&tx)?,
+            WatchMode::Auto => match native_watcher(&tx) {
+                Ok(watcher) => watcher,
+                Err(e) => {
+                    println!("Native file events unavailable ({}), polling instead", e);
+                    self.poll_watcher(&tx)?
+                }
+            },
+        };
+        self.watch_tree(watcher.as_mut())?;
+        if self.watch_limit_hit && settings.mode == WatchMode::Auto {
+            watcher = self.fall_back_to_polling(&tx).await?;
+        }
+        self.cache_renders();
         
-        let config = Config::default()
-            .with_poll_interval(Duration::from_millis(100));
+        let backend = match self.polling {
+            true => format!("polling every {}ms", settings.poll_interval_ms),
+            false => "native events".to_string(),
+        };
+        match &self.root.file {
+            Some(file) => println!("Started watching: {} (single file, {})", file.display(), backend),
+            None => println!(
+                "Started watching: {} ({} directories, {})",
+                self.root.dir.display(),
+                self.watched_dirs.len(),
+                backend
+            ),
+        }
         
-        let mut watcher = RecommendedWatcher::new(
+        loop {
+            tokio::select! {
+                result = rx.recv() => match result {
+                    Some(Ok(event)) => self.handle_file_event(watcher.as_mut(), event).await,
+                    Some(Err(e)) => println!("File watch error: {}", e),
+                    None => break,
+                },
+                _ = debounce::wait_until(self.debouncer.next_due()) => self.process_due_files().await,
+                Some(done) = render_rx.recv() => self.finish_render(done).await,
+                // the only change is to true
+                _ = stop.changed() => break,
+            }
+            if self.reload_pending {
+                self.reload_config(watcher.as_mut()).await;
+            } else if self.refilter_pending {
+                println!("Ignore rules changed, rescanning");
+                self.refilter(watcher.as_mut()).await;
+            }
+            if self.includes_changed {
+                self.watch_include_dirs(watcher.as_mut());
+            }
+            // e.g. a directory with many subdirectories was created
+            if self.watch_limit_hit && settings.mode == WatchMode::Auto {
+                watcher = self.fall_back_to_polling(&tx).await?;
+            }
+        }
+
+        // no more events once the watcher is gone; the pending ones are
+        // rendered right away instead of after their debounce
+        drop(watcher);
+        self.rendering.retain(|_, pending| {
+            if pending.cache_only {
+                pending.cancel.cancel();
+            }
+            !pending.cache_only
+        });
+        let pending = self.debouncer.take_all();
+        self.process_files(pending).await;
+        while !self.rendering.is_empty() {
+            let Some(done) = render_rx.recv().await else {
+                break;
+            };
+            self.finish_render(done).await;
+        }
+        println!("Stopped watching: {}", self.root.info().path);
+        Ok(())
+    }
+
+    fn poll_watcher(&mut self, tx: &EventSender) -> notify::Result<Box<dyn Watcher + Send>> {
+        let interval = self.root.config.get().watch.poll_interval();
+        let tx = tx.clone();
+        let watcher = PollWatcher::new(
             move |result| {
-                // Silently ignore channel send errors (happens when receiver is dropped)
-                let _ = tx.blocking_send(result);
+                let _ = tx.send(result);
             },
-            config,
+            Config::default().with_poll_interval(interval),
         )?;
-        
-        watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
-        
-        println!("Started watching: {}", path.as_ref().display());
-        
-        while let Some(result) = rx.recv().await {
-            match result {
-                Ok(event) => self.handle_file_event(event).await,
-                Err(e) => println!("File watch error: {}", e),
+        self.polling = true;
+        Ok(Box::new(watcher))
+    }
+
+    // The root's directory and everything below it that isn't excluded
+    fn watch_tree(&mut self, watcher: &mut (dyn Watcher + Send)) -> notify::Result<()> {
+        if let Err(e) = watcher.watch(&self.root.dir, RecursiveMode::NonRecursive) {
+            // out of watches already: in auto mode that falls back to polling,
+            // as for any other directory; otherwise nothing would be noticed
+            if !is_watch_limit(&e) || self.root.config.get().watch.mode != WatchMode::Auto {
+                return Err(e);
             }
+            let dir = self.root.dir.clone();
+            self.watch_failed(&dir, e);
+            return Ok(());
         }
-        
-        // Keep the watcher alive
-        std::mem::forget(watcher);
+        self.watched_dirs.insert(self.root.dir.clone());
+        self.update_watches(watcher);
         Ok(())
     }
 
-    async fn handle_file_event(&mut self, event: Event) {
-        // Only process meaningful events for markdown files
-        match event.kind {
-            EventKind::Create(_) | EventKind::Modify(_) => {
-                for path in event.paths {
-                    if self.is_markdown_file(&path) {
-                        println!("Markdown file event: {:?} - {}", event.kind, path.display());
-                        if self.should_process_file(&path).await {
-                            self.process_markdown_file(&path).await;
-                        }
-                    }
+    // Native watches ran out, so some directories would go unnoticed:
+    // everything is polled from now on instead
+    async fn fall_back_to_polling(&mut self, tx: &EventSender) -> notify::Result<Box<dyn Watcher + Send>> {
+        println!(
+            "Watch limit reached for {}, polling every {}ms instead (raise fs.inotify.max_user_watches to avoid this)",
+            self.root.dir.display(),
+            self.root.config.get().watch.poll_interval_ms
+        );
+        self.watch_limit_hit = false;
+        self.watched_dirs.clear();
+        let mut watcher = self.poll_watcher(tx)?;
+        self.watch_tree(watcher.as_mut())?;
+
+        // anything that changed while switching over
+        for path in self.indexed_files() {
+            self.debouncer.touch(path);
+        }
+        self.refilter(watcher.as_mut()).await;
+        Ok(watcher)
+    }
+
+    // Renders every file up front so the first change to each is already a
+    // patch, and a first event that changes nothing is skipped. One pool job
+    // per file, finished by the watcher loop like any other render, so events
+    // are handled meanwhile; the file index was filled by Workspace::scan.
+    fn cache_renders(&mut self) {
+        for path in self.root.documents() {
+            self.spawn_render(&path, None, true, read_file);
+        }
+    }
+
+    // Watches every directory below root that isn't excluded, each on its
+    // own rather than recursively, so ignored trees like node_modules cost no
+    // watches at all. Drops the watches of directories that are now excluded.
+    fn update_watches(&mut self, watcher: &mut (dyn Watcher + Send)) {
+        let mut wanted: HashSet<PathBuf> = self.watchable_dirs(&self.root.dir).into_iter().collect();
+        wanted.extend(self.include_dirs());
+        for dir in self.watched_dirs.difference(&wanted) {
+            let _ = watcher.unwatch(dir);
+        }
+        let added: Vec<PathBuf> = wanted.difference(&self.watched_dirs).cloned().collect();
+        for dir in added {
+            if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                self.watch_failed(&dir, e);
+            }
+        }
+        self.watched_dirs = wanted;
+    }
+
+    // Included files may be in directories that are excluded, or next to the
+    // file of a single-file root, which have no watch of their own
+    fn watch_include_dirs(&mut self, watcher: &mut (dyn Watcher + Send)) {
+        self.includes_changed = false;
+        for dir in self.include_dirs() {
+            if self.watched_dirs.contains(&dir) {
+                continue;
+            }
+            if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                self.watch_failed(&dir, e);
+                continue;
+            }
+            self.watched_dirs.insert(dir);
+        }
+    }
+
+    // e.g. an excluded _partials/ created again; its watch is added after
+    // the event, and files already in it are picked up then
+    fn watch_new_include_dir(&mut self, dir: &Path) {
+        let included: Vec<PathBuf> = self
+            .includes
+            .values()
+            .flatten()
+            .filter(|included| included.parent() == Some(dir))
+            .cloned()
+            .collect();
+        if included.is_empty() {
+            return;
+        }
+        self.includes_changed = true;
+        for path in included {
+            self.debouncer.touch(path);
+        }
+    }
+
+    // The directories of included files inside the root that exist
+    fn include_dirs(&self) -> HashSet<PathBuf> {
+        self.includes
+            .values()
+            .flatten()
+            .filter_map(|included| included.parent())
+            .filter(|dir| dir.starts_with(&self.root.dir) && dir.is_dir())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    // A directory that appeared, e.g. created or moved in. Files may have
+    // been written to it before its watch was in place, so they are picked
+    // up here.
+    fn watch_new_dir(&mut self, watcher: &mut (dyn Watcher + Send), dir: &Path) {
+        for dir in self.watchable_dirs(dir) {
+            if self.watched_dirs.contains(&dir) {
+                continue;
+            }
+            if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                self.watch_failed(&dir, e);
+                continue;
+            }
+            for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+                let path = entry.path();
+                if path.is_file() && self.is_relevant(&path) {
+                    self.debouncer.touch(path);
                 }
             }
-            EventKind::Remove(_) => {
-                for path in event.paths {
-                    if self.is_markdown_file(&path) {
-                        println!("Markdown file deleted: {}", path.display());
-                        let update_event = UpdateEvent::FileDeleted {
-                            file_path: path.to_string_lossy().to_string(),
-                        };
-                        self.connection_manager.send_update(update_event).await;
-                        self.watched_files.remove(&path);
+            self.watched_dirs.insert(dir);
+        }
+    }
+
+    fn watch_failed(&mut self, dir: &Path, error: notify::Error) {
+        if is_watch_limit(&error) {
+            // every watch after it fails the same way
+            if self.watch_limit_hit {
+                return;
+            }
+            self.watch_limit_hit = true;
+        }
+        println!("Failed to watch {}: {}", dir.display(), error);
+    }
+
+    // A directory that is gone; the files that were in it may not get
+    // events of their own, e.g. when it was moved out of the root
+    fn forget_dir(&mut self, watcher: &mut (dyn Watcher + Send), dir: &Path) {
+        let gone: Vec<PathBuf> = self.watched_dirs.iter().filter(|d| d.starts_with(dir)).cloned().collect();
+        for dir in gone {
+            let _ = watcher.unwatch(&dir);
+            self.watched_dirs.remove(&dir);
+        }
+        for path in self.indexed_files() {
+            if path.starts_with(dir) {
+                self.debouncer.touch(path);
+            }
+        }
+    }
+
+    // dir and the directories below it that aren't excluded. A single file
+    // only needs its own directory.
+    fn watchable_dirs(&self, dir: &Path) -> Vec<PathBuf> {
+        if self.root.file.is_some() {
+            return if dir == self.root.dir { vec![dir.to_path_buf()] } else { Vec::new() };
+        }
+        let mut dirs = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let relative = dir.strip_prefix(&self.root.dir).unwrap_or(&dir);
+            if !relative.as_os_str().is_empty() && self.filter.is_excluded(relative) {
+                continue;
+            }
+            for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+                if entry.path().is_dir() {
+                    pending.push(entry.path());
+                }
+            }
+            dirs.push(dir);
+        }
+        dirs
+    }
+
+    async fn handle_file_event(&mut self, watcher: &mut (dyn Watcher + Send), event: Event) {
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+            return;
+        }
+
+        // settled like markdown files, as one save is several events
+        for path in &event.paths {
+            if path == self.root.config.path() || self.is_ignore_file(path) {
+                self.debouncer.touch(path.clone());
+            }
+        }
+
+        if let EventKind::Modify(ModifyKind::Name(mode)) = event.kind {
+            self.track_rename(mode, &event);
+        }
+
+        for path in &event.paths {
+            if path.is_dir() {
+                if !self.watched_dirs.contains(path) {
+                    self.watch_new_dir(watcher, path);
+                    self.watch_new_include_dir(path);
+                }
+            } else if self.watched_dirs.contains(path) {
+                self.forget_dir(watcher, path);
+            }
+        }
+
+        // Only meaningful events for markdown files; what happened is looked
+        // up once the path has settled, since a save can show up as any mix
+        // of creates, writes, renames and removes
+        for path in event.paths {
+            if self.is_relevant(&path) || self.is_included(&path) {
+                println!("Markdown file event: {:?} - {}", event.kind, path.display());
+                self.debouncer.touch(path);
+            }
+        }
+    }
+
+    // Rebuilds the filter after an ignore file or the config changed, and
+    // brings the watches and the file index in line with it
+    async fn refilter(&mut self, watcher: &mut (dyn Watcher + Send)) {
+        self.refilter_pending = false;
+        let watch = self.root.config.get().watch;
+        self.filter = watch.filter_for(&self.root.dir).unwrap_or_default();
+        self.file_types = watch.file_types();
+        self.update_watches(watcher);
+
+        let current: HashSet<PathBuf> = self.root.documents().into_iter().collect();
+        for path in self.indexed_files() {
+            if !current.contains(&path) {
+                self.remove_markdown_file(&path).await;
+            }
+        }
+        for path in current {
+            if !self.workspace.files().contains(&self.root.client_path(&path)) {
+                self.process_markdown_file(&path).await;
+            }
+        }
+    }
+
+    // The files of this root in the index, as paths on disk
+    fn indexed_files(&self) -> Vec<PathBuf> {
+        self.workspace
+            .files()
+            .entries()
+            .iter()
+            .filter_map(|entry| self.workspace.resolve(&entry.path).ok())
+            .filter(|path| self.root.contains(path))
+            .collect()
+    }
+
+    // Backends report a rename as one event with both paths, as a From and
+    // a To event, or both
+    fn track_rename(&mut self, mode: RenameMode, event: &Event) {
+        match mode {
+            RenameMode::Both if event.paths.len() == 2 => self.record_rename(&event.paths[0], &event.paths[1]),
+            RenameMode::From => self.rename_from = event.paths.first().map(|from| (from.clone(), event.tracker())),
+            RenameMode::To => {
+                if let Some((from, tracker)) = self.rename_from.take()
+                    && tracker == event.tracker()
+                    && let Some(to) = event.paths.first()
+                {
+                    self.record_rename(&from, to);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Only markdown files renamed to markdown files are renames for clients;
+    // an editor moving a file aside or a temp file into place is a change
+    fn record_rename(&mut self, from: &Path, to: &Path) {
+        if to.is_dir() {
+            for old in self.indexed_files() {
+                if let Ok(below) = old.strip_prefix(from) {
+                    self.record_rename(&old, &to.join(below));
+                }
+            }
+        } else if self.is_relevant(from) && self.is_relevant(to) {
+            self.renames.insert(to.to_path_buf(), from.to_path_buf());
+        }
+    }
+
+    async fn process_due_files(&mut self) {
+        let paths = self.debouncer.take_due();
+        self.process_files(paths).await;
+    }
+
+    async fn process_files(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            if path == self.root.config.path() {
+                self.reload_pending = true;
+                continue;
+            }
+            if self.is_ignore_file(&path) {
+                self.refilter_pending = true;
+                continue;
+            }
+            // not served itself, only included by documents that are
+            if !self.is_relevant(&path) {
+                self.render_dependents(&path).await;
+                continue;
+            }
+            if let Some(old) = self.renames.remove(&path) {
+                if path.is_file() && !old.exists() && self.rename_markdown_file(&old, &path).await {
+                    // it may have been edited on the way
+                    self.process_markdown_file(&path).await;
+                    continue;
+                }
+                if !old.is_file() {
+                    self.remove_markdown_file(&old).await;
+                }
+            }
+
+            if path.is_file() {
+                self.process_markdown_file(&path).await;
+            } else if !self.renames.values().any(|old| *old == path) {
+                // otherwise it's dealt with once the new path is due
+                self.remove_markdown_file(&path).await;
+            }
+        }
+    }
+
+    // Returns false if the old path wasn't a known file
+    async fn rename_markdown_file(&mut self, old: &Path, path: &Path) -> bool {
+        let old_path = self.root.client_path(old);
+        let file_path = self.root.client_path(path);
+        if !self.workspace.files().rename(&old_path, &file_path) {
+            return false;
+        }
+        // clients keep their render, so the next change is still a patch
+        self.workspace.renders().rename(&old_path, &file_path);
+        // its result would land under the old path; the new one is processed next
+        self.cancel_render(old);
+        if let Some(metadata) = self.watched_files.remove(old) {
+            self.watched_files.insert(path.to_path_buf(), metadata);
+        }
+        if let Some(includes) = self.includes.remove(old) {
+            self.includes.insert(path.to_path_buf(), includes);
+        }
+        println!("Markdown file renamed: {} -> {}", old.display(), path.display());
+        let update_event = UpdateEvent::FileRenamed { old_path, file_path };
+        self.connection_manager.send_update(update_event).await;
+        // includes of the old path are broken now, ones of the new path work
+        self.render_dependents(old).await;
+        self.render_dependents(path).await;
+        true
+    }
+
+    async fn remove_markdown_file(&mut self, path: &Path) {
+        let file_path = self.root.client_path(path);
+        self.cancel_render(path);
+        self.watched_files.remove(path);
+        self.includes.remove(path);
+        self.render_dependents(path).await;
+        self.workspace.renders().remove(&file_path);
+        // e.g. an editor's temporary file that came and went
+        if !self.workspace.files().remove(&file_path) {
+            return;
+        }
+        println!("Markdown file deleted: {}", path.display());
+        let update_event = UpdateEvent::FileDeleted { file_path };
+        self.connection_manager.send_update(update_event).await;
+    }
+
+    async fn reload_config(&mut self, watcher: &mut (dyn Watcher + Send)) {
+        self.reload_pending = false;
+        match self.root.config.reload() {
+            Ok(config) => {
+                println!("Reloaded {}", self.root.config.path().display());
+                self.debouncer.set_durations(config.watch.debounce(), config.watch.max_wait());
+                self.refilter(watcher).await;
+
+                // render options may have changed, refresh what clients are
+                // looking at; the files are read on the render pool too
+                let paths: Vec<PathBuf> = self.watched_files.keys().cloned().collect();
+                for path in paths {
+                    if self.is_relevant(&path) {
+                        self.spawn_render(&path, None, false, read_file);
                     }
                 }
             }
-            _ => {} // Ignore Access, Modify metadata, etc.
+            Err(e) => {
+                println!("Keeping previous configuration: {}", e);
+                let error_event = UpdateEvent::Error {
+                    file_path: None,
+                    message: e,
+                };
+                self.connection_manager.send_update(error_event).await;
+            }
         }
     }
 
+    // Reads the file and hands parsing and rendering to the render pool; the
+    // result comes back to the watcher loop through finish_render
     async fn process_markdown_file(&mut self, path: &Path) {
-        println!("Processing markdown file: {}", path.display());
+        let file_path = self.root.client_path(path);
         
         match tokio::fs::read_to_string(path).await {
             Ok(content) => {
-                match parser::parse_markdown(&content) {
-                    Ok(document) => {
-                        let html = document.to_html();
-                        let update_event = UpdateEvent::ContentUpdate {
-                            file_path: path.to_string_lossy().to_string(),
-                            html,
-                        };
-                        self.connection_manager.send_update(update_event).await;
-                        
-                        // Update file metadata
-                        self.watched_files.insert(
-                            path.to_path_buf(),
-                            FileMetadata {
-                                last_modified: Instant::now(),
-                                content_hash: self.calculate_hash(&content),
-                            },
-                        );
-                    }
-                    Err(e) => {
-                        let error_event = UpdateEvent::Error {
-                            message: format!("Parse error in {}: {}", path.display(), e),
-                        };
-                        self.connection_manager.send_update(error_event).await;
-                    }
+                // e.g. a touch, or a save without changes; compared with a
+                // render still running if there is one, as that one wins
+                let hash = content_hash(&content);
+                let latest = match self.rendering.get(path) {
+                    Some(pending) => pending.content_hash,
+                    None => self.watched_files.get(path).map(|metadata| metadata.content_hash),
+                };
+                if latest == Some(hash) {
+                    println!("Markdown file unchanged: {}", path.display());
+                    return;
                 }
+                println!("Processing markdown file: {}", path.display());
+
+                // an edit made outside a collaborative session joins it
+                self.workspace
+                    .collab()
+                    .disk_changed(&self.connection_manager, &file_path, &content);
+
+                self.start_render(path, content, hash);
+                self.render_dependents(path).await;
             }
             Err(e) => {
                 println!("Failed to read file {}: {}", path.display(), e);
                 let error_event = UpdateEvent::Error {
-                    message: format!("Failed to read file {}: {}", path.display(), e),
+                    message: format!("Failed to read file {}: {}", file_path, e),
+                    file_path: Some(file_path),
                 };
                 self.connection_manager.send_update(error_event).await;
             }
         }
     }
 
-    pub async fn watch_single_file<P: AsRef<Path>>(&mut self, file_path: P) -> Result<(), Box<dyn std::error::Error>> {
-        let path = file_path.as_ref();
-        if !path.exists() {
-            return Err(format!("File does not exist: {}", path.display()).into());
+    // Supersedes a render of the same file that hasn't finished
+    fn start_render(&mut self, path: &Path, content: String, hash: u64) {
+        self.spawn_render(path, Some(hash), false, move |_| Ok(content));
+    }
+
+    // `read` runs on the pool; without a hash it reads the file from disk
+    fn spawn_render<R>(&mut self, path: &Path, content_hash: Option<u64>, cache_only: bool, read: R)
+    where
+        R: FnOnce(&Path) -> Result<String, String> + Send + 'static,
+    {
+        self.cancel_render(path);
+        self.next_render += 1;
+        let pending = PendingRender {
+            id: self.next_render,
+            content_hash,
+            cancel: Cancel::new(),
+            cache_only,
+        };
+        let cancel = pending.cancel.clone();
+        let id = pending.id;
+        self.rendering.insert(path.to_path_buf(), pending);
+
+        let workspace = self.workspace.clone();
+        let render_tx = self.render_tx.clone();
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            let pool = workspace.render_pool().clone();
+            let job = {
+                let path = path.clone();
+                move || render(&workspace, &path, &read(&path)?)
+            };
+            let result = match pool.run(&cancel, job).await {
+                Some(result) => result,
+                None if cancel.is_cancelled() => return,
+                None => Err("rendering failed".to_string()),
+            };
+            let _ = render_tx.send(RenderDone { path, id, result });
+        });
+    }
+
+    fn cancel_render(&mut self, path: &Path) {
+        if let Some(pending) = self.rendering.remove(path) {
+            pending.cancel.cancel();
+        }
+    }
+
+    fn set_includes(&mut self, path: &Path, includes: Vec<PathBuf>) {
+        if includes.is_empty() {
+            self.includes.remove(path);
+        } else if self.includes.get(path) != Some(&includes) {
+            self.includes.insert(path.to_path_buf(), includes);
+            self.includes_changed = true;
         }
-        
-        if path.extension().and_then(|s| s.to_str()) != Some("md") {
-            return Err(format!("Not a markdown file: {}", path.display()).into());
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        self.includes.values().any(|includes| includes.iter().any(|included| included == path))
+    }
+
+    // Re-renders the documents that include the file, so they show its new
+    // content, or that it's gone
+    async fn render_dependents(&mut self, path: &Path) {
+        let dependents: Vec<PathBuf> = self
+            .includes
+            .iter()
+            .filter(|(dependent, includes)| dependent.as_path() != path && includes.iter().any(|included| included == path))
+            .map(|(dependent, _)| dependent.clone())
+            .collect();
+        for dependent in dependents {
+            // a document that is gone has an event of its own
+            let Ok(content) = tokio::fs::read_to_string(&dependent).await else {
+                continue;
+            };
+            println!("Re-rendering {}, which includes {}", dependent.display(), path.display());
+            let hash = content_hash(&content);
+            self.start_render(&dependent, content, hash);
         }
-        
-        // Process the file once initially
-        self.process_markdown_file(path).await;
-        
-        // Watch the parent directory to catch file modifications
-        if let Some(parent) = path.parent() {
-            self.start_watching(parent).await
-        } else {
-            Err("Cannot determine parent directory".into())
+    }
+
+    async fn finish_render(&mut self, done: RenderDone) {
+        // superseded, or the file was removed or renamed meanwhile
+        if self.rendering.get(&done.path).is_none_or(|pending| pending.id != done.id) {
+            return;
+        }
+        let Some(pending) = self.rendering.remove(&done.path) else {
+            return;
+        };
+        let path = done.path.as_path();
+        let file_path = self.root.client_path(path);
+
+        match done.result {
+            Ok(rendered) if pending.cache_only => {
+                self.set_includes(path, rendered.includes);
+                self.workspace
+                    .renders()
+                    .get_or_insert(&file_path, rendered.blocks, rendered.source_lines);
+                self.watched_files.insert(
+                    done.path,
+                    FileMetadata {
+                        content_hash: rendered.content_hash,
+                    },
+                );
+            }
+            Err(_) if pending.cache_only => {}
+            Ok(rendered) => {
+                self.set_includes(path, rendered.includes);
+                let created = self
+                    .workspace
+                    .files()
+                    .upsert(path, &file_path, rendered.title, rendered.source_hash);
+                if created {
+                    let created_event = UpdateEvent::FileCreated {
+                        file_path: file_path.clone(),
+                    };
+                    self.connection_manager.send_update(created_event).await;
+                }
+
+                // clients holding the previous render only get the blocks that changed
+                let update_event = match self.workspace.renders().update(&file_path, rendered.blocks, rendered.source_lines) {
+                    RenderChange::Full { version, blocks } => Some(UpdateEvent::ContentUpdate {
+                        file_path,
+                        html: blocks_to_html(&blocks),
+                        version,
+                    }),
+                    RenderChange::Patch {
+                        version,
+                        base_version,
+                        ops,
+                    } => Some(UpdateEvent::ContentPatch {
+                        file_path,
+                        version,
+                        base_version,
+                        ops,
+                    }),
+                    RenderChange::Unchanged => None,
+                };
+                if let Some(update_event) = update_event {
+                    self.connection_manager.send_update(update_event).await;
+                }
+                
+                // Update file metadata
+                self.watched_files.insert(
+                    done.path,
+                    FileMetadata {
+                        content_hash: rendered.content_hash,
+                    },
+                );
+            }
+            Err(e) => {
+                let error_event = UpdateEvent::Error {
+                    message: format!("Parse error in {}: {}", file_path, e),
+                    file_path: Some(file_path),
+                };
+                self.connection_manager.send_update(error_event).await;
+            }
+        }
+    }
+
+    // An ignore file in a directory that is watched
+    fn is_ignore_file(&self, path: &Path) -> bool {
+        let is_named = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| IGNORE_FILE_NAMES.contains(&name));
+        is_named && path.parent().is_some_and(|dir| self.watched_dirs.contains(dir))
+    }
+
+    fn is_relevant(&self, path: &Path) -> bool {
+        if let Some(file) = &self.root.file {
+            return path == file;
+        }
+        self.filter.matches(path.strip_prefix(&self.root.dir).unwrap_or(path)) && self.file_types.is_document(path)
+    }
+}
+
+// A watcher running in its own task
+pub struct WatcherHandle {
+    pub name: String,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl WatcherHandle {
+    // Asks the watcher to render what is pending and finish
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+
+    pub async fn join(self) {
+        let _ = self.task.await;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl FileWatcher {
+    pub fn spawn(mut self) -> WatcherHandle {
+        let (stop, stopped) = watch::channel(false);
+        let name = self.root.name.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = self.start_watching(stopped).await {
+                eprintln!("Failed to start file watcher: {}", e);
+            }
+        });
+        WatcherHandle { name, stop, task }
+    }
+}
+
+// The running watchers, one per root, so roots can be added and removed
+// while the server runs
+#[derive(Clone)]
+pub struct Watchers {
+    connection_manager: ConnectionManager,
+    workspace: Workspace,
+    handles: Arc<Mutex<HashMap<String, WatcherHandle>>>,
+    // false with --no-watch: roots are served as they were scanned
+    enabled: bool,
+}
+
+impl Watchers {
+    pub fn new(connection_manager: ConnectionManager, workspace: Workspace, enabled: bool) -> Self {
+        Self {
+            connection_manager,
+            workspace,
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            enabled,
+        }
+    }
+
+    pub fn start(&self, root: Root) {
+        if !self.enabled {
+            return;
+        }
+        let name = root.name.clone();
+        let handle = FileWatcher::new(self.connection_manager.clone(), self.workspace.clone(), root).spawn();
+        if let Some(previous) = self.handles.lock().unwrap().insert(name, handle) {
+            previous.stop();
+        }
+    }
+
+    // Waits for the root's watcher to finish its pending renders
+    pub async fn stop(&self, name: &str) {
+        let handle = self.handles.lock().unwrap().remove(name);
+        if let Some(handle) = handle {
+            handle.stop();
+            handle.join().await;
+        }
+    }
+
+    pub async fn stop_all(&self) {
+        let handles: Vec<WatcherHandle> = self.handles.lock().unwrap().drain().map(|(_, handle)| handle).collect();
+        for handle in &handles {
+            handle.stop();
+        }
+        for handle in handles {
+            handle.join().await;
+        }
+    }
+
+    // Stops every watcher and starts one for each of the workspace's roots,
+    // e.g. after roots were added or removed without going through start
+    pub async fn restart(&self) {
+        self.stop_all().await;
+        for root in self.workspace.roots() {
+            self.start(root);
         }
     }
+}
+
+fn native_watcher(tx: &EventSender) -> notify::Result<Box<dyn Watcher + Send>> {
+    let tx = tx.clone();
+    let watcher = RecommendedWatcher::new(
+        move |result| {
+            // Silently ignore channel send errors (happens when receiver is dropped)
+            let _ = tx.send(result);
+        },
+        Config::default(),
+    )?;
+    Ok(Box::new(watcher))
+}
 
-    fn is_markdown_file(&self, path: &Path) -> bool {
-        path.extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.to_lowercase() == "md")
-            .unwrap_or(false)
+// inotify reports running out of watches as ENOSPC
+fn is_watch_limit(error: &notify::Error) -> bool {
+    match &error.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        notify::ErrorKind::Io(e) => e.raw_os_error() == Some(28),
+        _ => false,
     }
+}
+
+// For renders of what is on disk, on the render pool
+fn read_file(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| e.to_string())
+}
 
-    async fn should_process_file(&mut self, path: &Path) -> bool {
-        // Debouncing logic: check if enough time has passed since last processing
-        if let Some(metadata) = self.watched_files.get(path) {
-            let elapsed = metadata.last_modified.elapsed();
-            if elapsed < self.debounce_duration {
-                return false; // Too soon, skip this update
+// Runs on the render pool
+fn render(workspace: &Workspace, path: &Path, content: &str) -> Result<Rendered, String> {
+    let document = workspace.parse_file(path, content)?;
+    let title = document.title();
+    let expanded = workspace.expand_includes(path, document);
+    Ok(Rendered {
+        content_hash: content_hash(content),
+        source_hash: workspace::source_hash(content),
+        title,
+        blocks: workspace.render_blocks(path, &expanded.document),
+        source_lines: expanded.document.source_lines,
+        includes: expanded.dependencies,
+    })
+}
+
+// Used to tell whether a file's content really changed. 64-bit FNV-1a: the
+// same content hashes the same across runs and builds, unlike DefaultHasher,
+// so clients can keep the hash as a version (see workspace::source_hash).
+pub fn content_hash(content: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    content
+        .bytes()
+        .fold(OFFSET_BASIS, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME))
+}
+
+// Recursively collects markdown and text files under root, sorted for stable output
+pub fn find_markdown_files(root: &Path, filter: &PathFilter, file_types: &FileTypes) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if path.is_dir() {
+                if !filter.is_excluded(relative) {
+                    pending.push(path);
+                }
+            } else if filter.matches(relative) && file_types.is_document(&path) {
+                files.push(path);
             }
         }
-        true
     }
 
-    fn calculate_hash(&self, content: &str) -> u64 {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        let mut hasher = DefaultHasher::new();
-        content.hash(&mut hasher);
-        hasher.finish()
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Overrides, SharedConfig};
+    use std::time::Duration;
+
+    // An empty directory of its own under the system's temp directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("markdawn-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
     }
-}
\ No newline at end of file
+
+    fn start(path: &Path, overrides: Overrides) -> (Workspace, ConnectionManager, WatcherHandle) {
+        let config = SharedConfig::load(path, overrides).unwrap();
+        let root = Root::new(path, "", config.clone()).unwrap();
+        let workspace = Workspace::new(config);
+        workspace.add_root(root.clone()).unwrap();
+        workspace.scan();
+        let connection_manager = ConnectionManager::new();
+        let handle = FileWatcher::new(connection_manager.clone(), workspace.clone(), root).spawn();
+        (workspace, connection_manager, handle)
+    }
+
+    // Whether the file's render comes to contain text within a few seconds
+    async fn renders_with(workspace: &Workspace, file_path: &str, text: &str) -> bool {
+        for _ in 0..100 {
+            let rendered = workspace.renders().get(file_path);
+            if rendered.is_some_and(|rendered| rendered.blocks.concat().contains(text)) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_config_writes_is_one_reload() {
+        let dir = temp_dir("config-burst");
+        std::fs::write(dir.join("index.md"), "hello").unwrap();
+        let overrides = Overrides {
+            debounce_ms: Some(100),
+            ..Overrides::default()
+        };
+        let (workspace, connection_manager, handle) = start(&dir, overrides);
+        let mut events = connection_manager.broadcast_tx.subscribe();
+        assert!(renders_with(&workspace, "index.md", "hello").await);
+
+        // an editor saving an invalid config: every write fails to load
+        for _ in 0..4 {
+            std::fs::write(dir.join("markdawn.toml"), "[server]\nport = \"none\"\n").unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let mut errors = 0;
+        while let Ok(sequenced) = events.try_recv() {
+            if let UpdateEvent::Error { file_path: None, .. } = sequenced.event {
+                errors += 1;
+            }
+        }
+        assert_eq!(errors, 1);
+
+        handle.stop();
+        handle.join().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "includes")]
+    #[tokio::test]
+    async fn includes_in_excluded_directories_rerender_their_dependents() {
+        let dir = temp_dir("excluded-include");
+        std::fs::create_dir(dir.join("_partials")).unwrap();
+        std::fs::write(dir.join("_partials/footer.md"), "old footer").unwrap();
+        std::fs::write(dir.join("index.md"), "# Index\n\n{{include _partials/footer.md}}\n").unwrap();
+        let overrides = Overrides {
+            exclude: vec!["**/_partials".to_string()],
+            debounce_ms: Some(20),
+            ..Overrides::default()
+        };
+        let (workspace, _, handle) = start(&dir, overrides);
+
+        assert!(renders_with(&workspace, "index.md", "old footer").await);
+        assert!(!workspace.files().contains("_partials/footer.md"));
+        // the watch on _partials/ is added once index.md's includes are known
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(dir.join("_partials/footer.md"), "new footer").unwrap();
+        assert!(renders_with(&workspace, "index.md", "new footer").await);
+        // still not served itself
+        assert!(!workspace.files().contains("_partials/footer.md"));
+
+        std::fs::remove_file(dir.join("_partials/footer.md")).unwrap();
+        assert!(renders_with(&workspace, "index.md", "Cannot include _partials/footer.md").await);
+
+        handle.stop();
+        handle.join().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "includes")]
+    #[tokio::test]
+    async fn a_single_file_can_include_the_files_next_to_it() {
+        let dir = temp_dir("single-file-include");
+        std::fs::write(dir.join("chapter.md"), "first draft").unwrap();
+        std::fs::write(dir.join("book.md"), "# Book\n\n{{include chapter.md}}\n").unwrap();
+        let overrides = Overrides {
+            debounce_ms: Some(20),
+            ..Overrides::default()
+        };
+        let (workspace, _, handle) = start(&dir.join("book.md"), overrides);
+
+        assert!(renders_with(&workspace, "book.md", "first draft").await);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(dir.join("chapter.md"), "second draft").unwrap();
+        assert!(renders_with(&workspace, "book.md", "second draft").await);
+        assert!(!workspace.files().contains("chapter.md"));
+
+        handle.stop();
+        handle.join().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
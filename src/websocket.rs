@@ -1,31 +1,119 @@
-use crate::events::UpdateEvent;
+use crate::events::{SequencedEvent, UpdateEvent};
+use crate::config::DEFAULT_BROADCAST_CAPACITY;
+use crate::outbox::{Outbox, Outgoing};
+use crate::patch::blocks_to_html;
+use crate::presence::{unix_now, ClientInfo, Presence};
+use crate::protocol::{ClientMessage, OutlineHeading, ServerMessage, PROTOCOL_VERSION};
+use crate::renderer::slugify;
+use crate::workspace::{SaveError, Workspace};
 use axum::{
-    extract::{ws::WebSocketUpgrade, ws::WebSocket, ws::Message, State},
-    response::Response,
+    extract::{ws::close_code, ws::CloseFrame, ws::WebSocketUpgrade, ws::WebSocket, ws::Message, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use futures_util::{SinkExt, StreamExt};
-use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use globset::{GlobBuilder, GlobMatcher};
+use axum::body::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::MissedTickBehavior;
+
+// How many recent events are kept for clients resuming a dropped connection
+const REPLAY_CAPACITY: usize = 256;
 
 #[derive(Clone)]
 pub struct ConnectionManager {
-    pub broadcast_tx: broadcast::Sender<UpdateEvent>,
-    pub connection_count: Arc<RwLock<usize>>,
+    pub broadcast_tx: broadcast::Sender<SequencedEvent>,
+    pub presence: Presence,
+    pub started_at: Instant,
+    // start of this run in unix seconds; event ids are only meaningful
+    // together with it, as they start over on every run
+    pub epoch: u64,
+    // when the last file or editing event was broadcast, in seconds since the
+    // Unix epoch; presence events don't count
+    last_event: Arc<Mutex<Option<u64>>>,
+    replay: Arc<Mutex<ReplayBuffer>>,
+    // set once the server shuts down; every connection then sends what it
+    // has queued and closes
+    closing: Arc<watch::Sender<bool>>,
+}
+
+// The last events broadcast. Ids are assigned while it is locked, so they
+// increase in the order the events go out.
+struct ReplayBuffer {
+    events: VecDeque<SequencedEvent>,
+    next_id: u64,
 }
 
 impl ConnectionManager {
     pub fn new() -> Self {
-        let (broadcast_tx, _) = broadcast::channel(100);
-        let connection_count = Arc::new(RwLock::new(0));
+        Self::with_capacity(DEFAULT_BROADCAST_CAPACITY)
+    }
+
+    // capacity is how many events a client can fall behind the broadcast
+    // before it misses some and needs a resync
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (broadcast_tx, _) = broadcast::channel(capacity);
+        let (closing, _) = watch::channel(false);
 
         Self {
             broadcast_tx,
-            connection_count,
+            presence: Presence::new(),
+            started_at: Instant::now(),
+            epoch: unix_now(),
+            last_event: Arc::new(Mutex::new(None)),
+            replay: Arc::new(Mutex::new(ReplayBuffer {
+                events: VecDeque::with_capacity(REPLAY_CAPACITY),
+                next_id: 1,
+            })),
+            closing: Arc::new(closing),
+        }
+    }
+
+    // Sends to every connected client without waiting, so it can be called
+    // while holding a lock. Fails if nobody is connected.
+    pub fn broadcast(&self, event: UpdateEvent) -> Result<usize, String> {
+        if !event.is_presence() {
+            *self.last_event.lock().unwrap() = Some(unix_now());
         }
+        let mut replay = self.replay.lock().unwrap();
+        let sequenced = SequencedEvent {
+            id: replay.next_id,
+            event,
+        };
+        replay.next_id += 1;
+        if replay.events.len() == REPLAY_CAPACITY {
+            replay.events.pop_front();
+        }
+        replay.events.push_back(sequenced.clone());
+        self.broadcast_tx
+            .send(sequenced)
+            .map_err(|_| "No clients connected".to_string())
+    }
+
+    // Subscribes to the broadcast and returns the events sent after
+    // `last_id`, or None if some of them are no longer kept
+    pub fn resume(&self, last_id: u64) -> (broadcast::Receiver<SequencedEvent>, Option<Vec<SequencedEvent>>) {
+        // locked so no event falls between the replay and the subscription
+        let replay = self.replay.lock().unwrap();
+        let rx = self.broadcast_tx.subscribe();
+        let oldest = replay.events.front().map_or(replay.next_id, |sequenced| sequenced.id);
+        if last_id + 1 < oldest || last_id >= replay.next_id {
+            return (rx, None);
+        }
+        let missed = replay.events.iter().filter(|sequenced| sequenced.id > last_id).cloned().collect();
+        (rx, Some(missed))
+    }
+
+    pub fn last_event(&self) -> Option<u64> {
+        *self.last_event.lock().unwrap()
     }
 
     pub async fn send_update(&self, event: UpdateEvent) {
-        match self.broadcast_tx.send(event) {
+        match self.broadcast(event) {
             Ok(_) => {
                 println!(
                     "Update broadcast to {} receivers",
@@ -38,45 +126,191 @@ impl ConnectionManager {
         }
     }
 
-    async fn increment_connections(&self) {
-        let mut count = self.connection_count.write().await;
-        *count += 1;
-        println!("WebSocket connection count increased to {}", *count);
+    // Registers a connection and announces it to the others
+    pub fn connect(&self) -> ClientInfo {
+        let client = self.presence.connect();
+        println!("WebSocket connection count increased to {}", self.presence.len());
+        let _ = self.broadcast(UpdateEvent::ClientJoined { client: client.clone() });
+        client
     }
 
-    async fn decrement_connections(&self) {
-        let mut count = self.connection_count.write().await;
-        *count -= 1;
-        println!("WebSocket connection count decreased to {}", *count);
+    pub fn disconnect(&self, client_id: u64) {
+        if self.presence.disconnect(client_id) {
+            let _ = self.broadcast(UpdateEvent::ClientLeft { client_id });
+        }
+        println!("WebSocket connection count decreased to {}", self.presence.len());
     }
 
-    pub async fn get_connection_count(&self) -> usize {
-        let count = self.connection_count.read().await;
-        *count
+    pub fn get_connection_count(&self) -> usize {
+        self.presence.len()
+    }
+
+    // Closes every WebSocket and SSE connection once the events broadcast
+    // so far have reached it. WebSocket clients get a 1001 close frame.
+    pub fn shutdown(&self) {
+        let _ = self.closing.send(true);
+    }
+
+    // Resolves once shutdown() was called
+    pub async fn closing(&self) {
+        let mut closing = self.closing.subscribe();
+        let _ = closing.wait_for(|closing| *closing).await;
+    }
+
+    // Waits for the connections to finish closing, up to timeout. Returns
+    // false if some are still open.
+    pub async fn wait_closed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.presence.is_empty() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        true
+    }
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
     State(connection_manager): State<ConnectionManager>,
+    State(workspace): State<Workspace>,
 ) -> Response {
-    ws.on_upgrade(move |socket| handle_socket(socket, connection_manager))
+    if !is_allowed_origin(&headers) {
+        println!("Refused WebSocket connection from origin {:?}", headers.get(header::ORIGIN));
+        return (StatusCode::FORBIDDEN, "Cross-origin WebSocket connections are not allowed").into_response();
+    }
+    ws.on_upgrade(move |socket| handle_socket(socket, connection_manager, workspace))
 }
 
-async fn handle_socket(socket: WebSocket, connection_manager: ConnectionManager) {
+// Browsers let any page open a WebSocket to localhost, and /ws can read and
+// write files, so a browser's connection has to come from a page served here
+// (Origin matches Host) or from another page on this machine. Clients that
+// aren't browsers send no Origin and are let through.
+fn is_allowed_origin(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let Some(authority) = origin.to_str().ok().and_then(|origin| origin.split_once("://")).map(|(_, rest)| rest) else {
+        return false;
+    };
+    let host = headers.get(header::HOST).and_then(|host| host.to_str().ok());
+    if host.is_some_and(|host| host.eq_ignore_ascii_case(authority)) {
+        return true;
+    }
+    // the host without the port; IPv6 addresses are in brackets
+    let origin_host = match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    origin_host.eq_ignore_ascii_case("localhost") || origin_host == "127.0.0.1" || origin_host == "::1"
+}
+
+// What a single client wants to hear about. A client that never subscribed
+// receives every event, like before subscriptions existed.
+#[derive(Default)]
+pub struct Subscriptions {
+    patterns: Vec<(String, GlobMatcher)>,
+    client_id: u64,
+    // files this client is editing collaboratively
+    editing: HashSet<String>,
+}
+
+impl Subscriptions {
+    pub fn new(client_id: u64) -> Self {
+        Self {
+            client_id,
+            ..Self::default()
+        }
+    }
+
+    pub fn add(&mut self, path: &str) -> Result<(), String> {
+        let matcher = GlobBuilder::new(path)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("Invalid subscription '{}': {}", path, e))?
+            .compile_matcher();
+        self.remove(path);
+        self.patterns.push((path.to_string(), matcher));
+        Ok(())
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.iter().map(|(pattern, _)| pattern.clone()).collect()
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.patterns.retain(|(pattern, _)| pattern != path);
+    }
+
+    pub fn wants(&self, event: &UpdateEvent) -> bool {
+        if event.is_collab() {
+            let own_peer = matches!(event, UpdateEvent::PeerUpdate { peer, .. } if peer.client_id == self.client_id);
+            return !own_peer && event.file_path().is_some_and(|path| self.editing.contains(path));
+        }
+        // subscribers to either name hear about a rename
+        if let UpdateEvent::FileRenamed { old_path, file_path } = event
+            && !self.patterns.is_empty()
+        {
+            return self
+                .patterns
+                .iter()
+                .any(|(_, matcher)| matcher.is_match(old_path) || matcher.is_match(file_path));
+        }
+        match event.file_path() {
+            Some(file_path) if !self.patterns.is_empty() => {
+                self.patterns.iter().any(|(_, matcher)| matcher.is_match(file_path))
+            }
+            // unsubscribed clients and errors without a file get everything
+            _ => true,
+        }
+    }
+}
+
+async fn handle_socket(socket: WebSocket, connection_manager: ConnectionManager, workspace: Workspace) {
     println!("New websocket connnected");
-    connection_manager.increment_connections().await;
+    let server_config = workspace.config().get().server;
+    // subscribed first so the client hears its own client_joined
     let mut rx = connection_manager.broadcast_tx.subscribe();
+    let client_id = connection_manager.connect().client_id;
     let (mut sender, mut receiver) = socket.split();
-    let recv_task = tokio::spawn(async move {
+
+    let subscriptions = Arc::new(Mutex::new(Subscriptions::new(client_id)));
+    // replies to this client only (handshake, responses, initial renders),
+    // bypassing the subscription filter and the outbox
+    let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<ServerMessage>();
+    let _ = direct_tx.send(ServerMessage::hello(None, client_id));
+    // push events for this client, waiting to be sent
+    let outbox = Arc::new(Outbox::new(server_config.send_queue));
+    // when the client last sent anything, pongs included
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
+
+    let recv_subscriptions = subscriptions.clone();
+    let recv_connection_manager = connection_manager.clone();
+    let recv_workspace = workspace.clone();
+    let recv_last_seen = last_seen.clone();
+    let mut recv_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
+            *recv_last_seen.lock().unwrap() = Instant::now();
             match msg {
-                Ok(Message::Text(_)) => {
-                    println!("Received text message from client");
-                }
-                Ok(Message::Ping(data)) => {
-                    println!("received ping, should send pong");
+                Ok(Message::Text(text)) => {
+                    let replies =
+                        handle_client_message(&text, &recv_subscriptions, &recv_workspace, &recv_connection_manager).await;
+                    for reply in replies {
+                        if direct_tx.send(reply).is_err() {
+                            return;
+                        }
+                    }
                 }
+                // the pong is sent by the websocket library
+                Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
                 Ok(Message::Close(_)) => {
                     println!("Client closed conenction");
                     break;
@@ -92,32 +326,457 @@ async fn handle_socket(socket: WebSocket, connection_manager: ConnectionManager)
         }
     });
 
-    let send_task = tokio::spawn(async move {
-        while let Ok(event) = rx.recv().await {
-            match event.to_json() {
-                Ok(json_str) => {
-                    if sender.send(Message::Text(json_str.into())).await.is_err() {
-                        println!("Failed to send message to client");
+    // moves broadcast events into the outbox as fast as they come, so the
+    // broadcast never waits for this client's socket
+    let pump_subscriptions = subscriptions.clone();
+    let pump_outbox = outbox.clone();
+    let pump_connection_manager = connection_manager.clone();
+    let mut pump_task = tokio::spawn(async move {
+        let mut closed = false;
+        loop {
+            let received = tokio::select! {
+                received = rx.recv() => received,
+                _ = pump_connection_manager.closing(), if !closed => {
+                    // what was broadcast before the shutdown still goes out,
+                    // then the close frame
+                    while let Ok(SequencedEvent { event, .. }) = rx.try_recv() {
+                        queue_event(&pump_subscriptions, &pump_outbox, client_id, event);
+                    }
+                    pump_outbox.close();
+                    closed = true;
+                    continue;
+                }
+            };
+            match received {
+                Ok(SequencedEvent { event, .. }) => queue_event(&pump_subscriptions, &pump_outbox, client_id, event),
+                Err(RecvError::Lagged(missed)) => {
+                    println!("Client {} missed {} updates", client_id, missed);
+                    pump_outbox.overflow(format!("Missed {} updates", missed));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let send_subscriptions = subscriptions.clone();
+    let send_connection_manager = connection_manager.clone();
+    let send_workspace = workspace.clone();
+    let mut send_task = tokio::spawn(async move {
+        let mut heartbeat = tokio::time::interval(server_config.heartbeat());
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // revision each editing session restarted from in the last resync;
+        // operations up to it are already in the content sent then
+        let mut resynced: HashMap<String, u64> = HashMap::new();
+        loop {
+            let messages = tokio::select! {
+                outgoing = outbox.next() => match outgoing {
+                    Outgoing::Message(message) => vec![message],
+                    Outgoing::Resync(reason) => {
+                        println!("Resyncing client {}: {}", client_id, reason);
+                        resync(reason, &send_subscriptions, &send_workspace, &send_connection_manager, &mut resynced).await
+                    }
+                    Outgoing::Close => {
+                        let frame = CloseFrame {
+                            code: close_code::AWAY,
+                            reason: "Server shutting down".into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(frame))).await;
                         break;
                     }
+                },
+                direct = direct_rx.recv() => match direct {
+                    Some(message) => vec![message],
+                    None => break,
+                },
+                _ = heartbeat.tick() => {
+                    if last_seen.lock().unwrap().elapsed() > server_config.idle_timeout() {
+                        println!("Client {} stopped responding, disconnecting", client_id);
+                        let _ = sender.send(Message::Close(None)).await;
+                        break;
+                    }
+                    if sender.send(Message::Ping(Bytes::new())).await.is_err() {
+                        break;
+                    }
+                    continue;
                 }
-                Err(e) => {
-                    println!("Failed to serialize event: {} ", e);
+            };
+
+            for message in messages {
+                if let ServerMessage::EditAck { path, revision } | ServerMessage::RemoteEdit { path, revision, .. } =
+                    &message
+                    && resynced.get(path).is_some_and(|resynced| revision <= resynced)
+                {
+                    continue;
+                }
+                match message.to_json() {
+                    Ok(json_str) => {
+                        if sender.send(Message::Text(json_str.into())).await.is_err() {
+                            println!("Failed to send message to client");
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        println!("Failed to serialize event: {} ", e);
+                    }
                 }
             }
         }
     });
 
     tokio::select! {
-        _ = recv_task => {
+        _ = &mut recv_task => {
             println!("receive task complete");
         }
-        _ = send_task => {
+        _ = &mut send_task => {
             println!("send task complete");
         }
+        _ = &mut pump_task => {
+            println!("broadcast closed");
+        }
+    }
+    recv_task.abort();
+    send_task.abort();
+    pump_task.abort();
+
+    let editing: Vec<String> = subscriptions.lock().unwrap().editing.drain().collect();
+    for path in editing {
+        workspace.collab().leave(&connection_manager, &path, client_id);
     }
 
-    connection_manager.decrement_connections().await;
+    connection_manager.disconnect(client_id);
     println!("Websocket conectino closed and cleaned up");
 }
 
+// Puts a broadcast event in a client's outbox if it wants it
+fn queue_event(subscriptions: &Mutex<Subscriptions>, outbox: &Outbox, client_id: u64, event: UpdateEvent) {
+    if !subscriptions.lock().unwrap().wants(&event) {
+        return;
+    }
+    outbox.push(match event {
+        // sent through the broadcast like everyone else's edits, so the
+        // client sees its ack in the same order as the other operations
+        UpdateEvent::CollabEdit { file_path, origin, revision, .. } if origin == client_id => {
+            ServerMessage::EditAck { path: file_path, revision }
+        }
+        event => ServerMessage::from(event),
+    });
+}
+
+// The state of everything a client follows, for when its queued events were
+// dropped: renders of its subscribed (or viewed) files, its editing
+// sessions and who is connected
+pub async fn resync(
+    reason: String,
+    subscriptions: &Mutex<Subscriptions>,
+    workspace: &Workspace,
+    connection_manager: &ConnectionManager,
+    resynced: &mut HashMap<String, u64>,
+) -> Vec<ServerMessage> {
+    let (client_id, patterns, editing) = {
+        let subscriptions = subscriptions.lock().unwrap();
+        let editing: Vec<String> = subscriptions.editing.iter().cloned().collect();
+        (subscriptions.client_id, subscriptions.patterns(), editing)
+    };
+    let mut messages = vec![ServerMessage::Resync { reason }];
+
+    let patterns = if patterns.is_empty() {
+        // unsubscribed clients get everything, but only need what they show
+        connection_manager
+            .presence
+            .get(client_id)
+            .and_then(|client| client.viewing)
+            .into_iter()
+            .collect()
+    } else {
+        patterns
+    };
+    for pattern in patterns {
+        messages.extend(initial_render(&pattern, workspace).await);
+    }
+
+    for path in editing {
+        if let Some(snapshot) = workspace.collab().snapshot(&path, client_id) {
+            resynced.insert(path.clone(), snapshot.revision);
+            messages.push(ServerMessage::EditJoined {
+                id: None,
+                path,
+                client_id,
+                revision: snapshot.revision,
+                content: snapshot.content,
+                peers: snapshot.peers,
+            });
+        }
+    }
+
+    messages.push(ServerMessage::ClientList {
+        id: None,
+        clients: connection_manager.presence.clients(),
+    });
+    messages
+}
+
+// Handles one request and returns the messages to send back
+async fn handle_client_message(
+    text: &str,
+    subscriptions: &Mutex<Subscriptions>,
+    workspace: &Workspace,
+    connection_manager: &ConnectionManager,
+) -> Vec<ServerMessage> {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => return vec![ServerMessage::error(None, format!("Invalid message: {}", e))],
+    };
+
+    match message {
+        ClientMessage::Hello {
+            id,
+            protocol_version,
+            client,
+            name,
+        } => {
+            if protocol_version != PROTOCOL_VERSION {
+                return vec![ServerMessage::error(
+                    id,
+                    format!(
+                        "Unsupported protocol version {}, server speaks {}",
+                        protocol_version, PROTOCOL_VERSION
+                    ),
+                )];
+            }
+            println!("Client identified as {}", client.as_deref().unwrap_or("unknown"));
+            let client_id = subscriptions.lock().unwrap().client_id;
+            if let Some(client) = connection_manager.presence.set_name(client_id, name) {
+                let _ = connection_manager.broadcast(UpdateEvent::ClientUpdated { client });
+            }
+            vec![ServerMessage::hello(id, client_id)]
+        }
+        ClientMessage::View { id, path } => {
+            // keyed like the watcher's paths, so clients can compare them
+            let path = match path.map(|path| workspace.resolve(&path)).transpose() {
+                Ok(full_path) => full_path.map(|full_path| workspace.relative_path(&full_path)),
+                Err(e) => return vec![ServerMessage::error(id, e)],
+            };
+            let client_id = subscriptions.lock().unwrap().client_id;
+            if let Some(client) = connection_manager.presence.set_viewing(client_id, path) {
+                let _ = connection_manager.broadcast(UpdateEvent::ClientUpdated { client });
+            }
+            vec![ServerMessage::Ack { id }]
+        }
+        ClientMessage::ListClients { id } => vec![ServerMessage::ClientList {
+            id,
+            clients: connection_manager.presence.clients(),
+        }],
+        ClientMessage::Subscribe { id, path } => {
+            if let Err(message) = subscriptions.lock().unwrap().add(&path) {
+                return vec![ServerMessage::error(id, message)];
+            }
+            println!("Client subscribed to {}", path);
+            let mut replies = vec![ServerMessage::Ack { id }];
+            replies.extend(initial_render(&path, workspace).await);
+            replies
+        }
+        ClientMessage::Unsubscribe { id, path } => {
+            subscriptions.lock().unwrap().remove(&path);
+            println!("Client unsubscribed from {}", path);
+            vec![ServerMessage::Ack { id }]
+        }
+        ClientMessage::Render { id, content } => vec![match workspace.render_text(&content) {
+            Ok(html) => ServerMessage::RenderResult { id, html },
+            Err(e) => ServerMessage::error(id, format!("Parse error: {}", e)),
+        }],
+        ClientMessage::ListFiles { id } => vec![ServerMessage::FileList {
+            id,
+            files: workspace.files().entries().into_iter().map(|entry| entry.path).collect(),
+        }],
+        ClientMessage::GetFileTree { id } => vec![ServerMessage::FileTree {
+            id,
+            tree: workspace.files().tree(),
+        }],
+        ClientMessage::GetSource { id, path } => vec![match workspace.read_source(&path).await {
+            Ok(source) => ServerMessage::Source {
+                id,
+                path,
+                content: source.content,
+                hash: source.hash,
+            },
+            Err(e) => ServerMessage::error(id, e),
+        }],
+        ClientMessage::Save {
+            id,
+            path,
+            content,
+            base_hash,
+        } => vec![match workspace.save_file(&path, &content, base_hash.as_deref()).await {
+            Ok(hash) => {
+                println!("Saved {} from the browser", path);
+                ServerMessage::Saved { id, path, hash }
+            }
+            Err(SaveError::Conflict { current_hash, message }) => ServerMessage::Conflict {
+                id,
+                path,
+                hash: current_hash,
+                message,
+            },
+            Err(SaveError::Invalid(message)) | Err(SaveError::Failed(message)) => ServerMessage::error(id, message),
+        }],
+        ClientMessage::JoinEdit { id, path, name } => {
+            // sessions are keyed like the watcher's paths; the reply tells the
+            // client which path to use from now on
+            let path = match workspace.resolve(&path) {
+                Ok(full_path) => workspace.relative_path(&full_path),
+                Err(e) => return vec![ServerMessage::error(id, e)],
+            };
+            let client_id = {
+                let mut subscriptions = subscriptions.lock().unwrap();
+                // joined before the snapshot is taken, so no later edit is missed
+                subscriptions.editing.insert(path.clone());
+                subscriptions.client_id
+            };
+            let name = name
+                .or_else(|| connection_manager.presence.get(client_id).and_then(|client| client.name))
+                .unwrap_or_else(|| format!("Guest {}", client_id));
+            match workspace.collab().join(workspace, connection_manager, &path, client_id, name).await {
+                Ok(snapshot) => {
+                    println!("Client {} is editing {}", client_id, path);
+                    vec![ServerMessage::EditJoined {
+                        id,
+                        path,
+                        client_id,
+                        revision: snapshot.revision,
+                        content: snapshot.content,
+                        peers: snapshot.peers,
+                    }]
+                }
+                Err(e) => {
+                    subscriptions.lock().unwrap().editing.remove(&path);
+                    vec![ServerMessage::error(id, e)]
+                }
+            }
+        }
+        ClientMessage::LeaveEdit { id, path } => {
+            let client_id = {
+                let mut subscriptions = subscriptions.lock().unwrap();
+                subscriptions.editing.remove(&path);
+                subscriptions.client_id
+            };
+            workspace.collab().leave(connection_manager, &path, client_id);
+            vec![ServerMessage::Ack { id }]
+        }
+        ClientMessage::Edit {
+            path,
+            revision,
+            operation,
+        } => {
+            let client_id = subscriptions.lock().unwrap().client_id;
+            match workspace.collab().edit(connection_manager, &path, client_id, revision, operation) {
+                Ok(()) => {
+                    workspace.collab().schedule_save(workspace, &path);
+                    Vec::new()
+                }
+                Err(message) => vec![ServerMessage::Error {
+                    id: None,
+                    path: Some(path),
+                    message,
+                }],
+            }
+        }
+        ClientMessage::Selection {
+            path,
+            revision,
+            anchor,
+            head,
+        } => {
+            let client_id = subscriptions.lock().unwrap().client_id;
+            workspace
+                .collab()
+                .select(connection_manager, &path, client_id, revision, anchor, head);
+            Vec::new()
+        }
+        ClientMessage::Cursor { path, line } => {
+            // relayed to everyone watching the file, through the broadcast
+            match workspace.rendered_file(&path).await {
+                Ok(rendered) => {
+                    if let Some(block) = rendered.block_at_line(line) {
+                        let scroll_event = UpdateEvent::ScrollTo {
+                            file_path: path,
+                            line,
+                            block,
+                        };
+                        connection_manager.send_update(scroll_event).await;
+                    }
+                    Vec::new()
+                }
+                Err(e) => vec![ServerMessage::Error {
+                    id: None,
+                    path: Some(path),
+                    message: e,
+                }],
+            }
+        }
+        ClientMessage::GetContent { id, path } => vec![content_update(id, path, workspace).await],
+        ClientMessage::GetOutline { id, path } => {
+            let document = match (workspace.resolve_document(&path), workspace.read_file(&path).await) {
+                (Ok(full_path), Ok(content)) => workspace
+                    .parse_file(&full_path, &content)
+                    .map(|document| workspace.expand_includes(&full_path, document).document),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            };
+            vec![match document {
+                Ok(document) => ServerMessage::Outline {
+                    id,
+                    path,
+                    headings: document
+                        .headings()
+                        .into_iter()
+                        .map(|(level, text)| OutlineHeading {
+                            level,
+                            anchor: slugify(&text),
+                            text,
+                        })
+                        .collect(),
+                },
+                Err(e) => ServerMessage::error(id, e),
+            }]
+        }
+    }
+}
+
+// Current content of every file matching a new subscription
+pub async fn initial_render(pattern: &str, workspace: &Workspace) -> Vec<ServerMessage> {
+    let is_glob = pattern.contains(['*', '?', '[', '{']);
+    let files = if is_glob {
+        let matcher = match GlobBuilder::new(pattern).literal_separator(true).build() {
+            Ok(glob) => glob.compile_matcher(),
+            Err(_) => return Vec::new(),
+        };
+        workspace
+            .markdown_files()
+            .into_iter()
+            .filter(|file| matcher.is_match(file))
+            .collect()
+    } else {
+        vec![pattern.to_string()]
+    };
+
+    let mut messages = Vec::new();
+    for path in files {
+        messages.push(content_update(None, path, workspace).await);
+    }
+    messages
+}
+
+async fn content_update(id: Option<u64>, path: String, workspace: &Workspace) -> ServerMessage {
+    match workspace.rendered_file(&path).await {
+        Ok(rendered) => ServerMessage::ContentUpdate {
+            id,
+            html: blocks_to_html(&rendered.blocks),
+            version: rendered.version,
+            path,
+        },
+        Err(message) => ServerMessage::Error {
+            id,
+            path: Some(path),
+            message,
+        },
+    }
+}
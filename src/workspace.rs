@@ -0,0 +1,349 @@
+use crate::collab::CollabHub;
+use crate::config::SharedConfig;
+use crate::data::Document;
+use crate::file_index::FileIndex;
+use crate::file_types::FileKind;
+use crate::include::{self, Expanded};
+use crate::parser;
+use crate::patch::{RenderCache, RenderedFile};
+use crate::render_pool::RenderPool;
+use crate::root::Root;
+use crate::watcher;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+
+// The roots being served together with the server's configuration. Clients
+// only ever see paths relative to a root, with `/` separators, prefixed with
+// the root's name unless it is the unnamed top-level one.
+#[derive(Clone)]
+pub struct Workspace {
+    roots: Arc<RwLock<Vec<Root>>>,
+    // the server settings, and the fallback for anything outside a root
+    config: SharedConfig,
+    files: FileIndex,
+    renders: RenderCache,
+    collab: CollabHub,
+    // shared by every root's watcher
+    render_pool: RenderPool,
+    // one save at a time, so two editors can't both pass the conflict check
+    save_lock: Arc<Mutex<()>>,
+}
+
+// A file's markdown source with the hash an editor hands back when saving
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub content: String,
+    pub hash: String,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    // the file changed since the editor loaded it; None if it was deleted
+    Conflict { current_hash: Option<String>, message: String },
+    Invalid(String),
+    Failed(String),
+}
+
+impl Workspace {
+    // Starts out without roots; see add_root
+    pub fn new(config: SharedConfig) -> Self {
+        let render_pool = RenderPool::new(config.get().server.render_workers);
+        Self {
+            roots: Arc::new(RwLock::new(Vec::new())),
+            config,
+            files: FileIndex::new(),
+            renders: RenderCache::new(),
+            collab: CollabHub::new(),
+            render_pool,
+            save_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn roots(&self) -> Vec<Root> {
+        self.roots.read().unwrap().clone()
+    }
+
+    // Names are unique, and only one root may be unnamed. Roots can't
+    // overlap, so every file belongs to exactly one.
+    pub fn add_root(&self, root: Root) -> Result<(), String> {
+        let mut roots = self.roots.write().unwrap();
+        if let Some(other) = roots.iter().find(|other| other.name == root.name) {
+            return Err(match root.name.as_str() {
+                "" => format!("{} is already served at the top level", other.info().path),
+                name => format!("A root named '{}' already exists", name),
+            });
+        }
+        if let Some(other) = roots.iter().find(|other| other.overlaps(&root)) {
+            return Err(format!("{} overlaps {}", root.info().path, other.info().path));
+        }
+        roots.push(root);
+        Ok(())
+    }
+
+    // Takes a root out along with its files' index entries and renders
+    pub fn remove_root(&self, name: &str) -> Result<Root, String> {
+        let owned: Vec<String> = self
+            .files
+            .entries()
+            .into_iter()
+            .map(|entry| entry.path)
+            .filter(|path| self.root_of_client_path(path).is_some_and(|owner| owner.name == name))
+            .collect();
+        let root = {
+            let mut roots = self.roots.write().unwrap();
+            let index = roots
+                .iter()
+                .position(|root| root.name == name)
+                .ok_or_else(|| format!("No root named '{}'", name))?;
+            roots.remove(index)
+        };
+        for path in owned {
+            self.files.remove(&path);
+            self.renders.remove(&path);
+        }
+        Ok(root)
+    }
+
+    // The root a path on disk is in
+    pub fn root_of(&self, path: &Path) -> Option<Root> {
+        self.roots.read().unwrap().iter().find(|root| root.contains(path)).cloned()
+    }
+
+    // The configuration that applies to a file: its root's
+    pub fn config_for(&self, path: &Path) -> SharedConfig {
+        self.root_of(path).map(|root| root.config).unwrap_or_else(|| self.config.clone())
+    }
+
+    pub fn config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    pub fn files(&self) -> &FileIndex {
+        &self.files
+    }
+
+    // Files being edited together in the browser
+    pub fn collab(&self) -> &CollabHub {
+        &self.collab
+    }
+
+    // Last render of each watched file, which content patches are diffed against
+    pub fn renders(&self) -> &RenderCache {
+        &self.renders
+    }
+
+    // Where the watchers parse and render changed files
+    pub fn render_pool(&self) -> &RenderPool {
+        &self.render_pool
+    }
+
+    // Fills the file index with everything currently on disk
+    pub fn scan(&self) {
+        for root in self.roots() {
+            self.scan_root(&root);
+        }
+    }
+
+    pub fn scan_root(&self, root: &Root) {
+        for path in root.documents() {
+            let Ok(content) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let title = self.parse_file(&path, &content).ok().and_then(|document| document.title());
+            self.files.upsert(&path, &root.client_path(&path), title, source_hash(&content));
+        }
+    }
+
+    // "/abs/root/docs/a.md" -> "docs/a.md", or "<name>/docs/a.md" in a named root
+    pub fn relative_path(&self, path: &Path) -> String {
+        match self.root_of(path) {
+            Some(root) => root.client_path(path),
+            None => path.to_string_lossy().to_string(),
+        }
+    }
+
+    // Like resolve, but only for the documents being served: the ones in the
+    // file index, which are of a document type and not excluded by their
+    // root's filter or ignore files. Everything else in a root, like .env
+    // or .git/config, can't be read or rendered through the server.
+    pub fn resolve_document(&self, relative: &str) -> Result<PathBuf, String> {
+        let path = self.resolve(relative)?;
+        if !self.files.contains(&self.relative_path(&path)) {
+            return Err(format!("Not found: {}", relative));
+        }
+        Ok(path)
+    }
+
+    // Like resolve_document, but also for a file that doesn't exist (or
+    // hasn't been indexed) yet, as long as its root would serve it
+    pub fn resolve_writable(&self, relative: &str) -> Result<PathBuf, String> {
+        let path = self.resolve(relative)?;
+        let served = self.files.contains(&self.relative_path(&path))
+            || self.root_of(&path).is_some_and(|root| root.admits(&path));
+        if !served {
+            return Err(format!("Not a served markdown or text file: {}", relative));
+        }
+        Ok(path)
+    }
+
+    // "docs/a.md" -> "/abs/root/docs/a.md", refusing anything outside the
+    // roots. A first segment naming a root wins over a top-level directory
+    // of the same name.
+    pub fn resolve(&self, relative: &str) -> Result<PathBuf, String> {
+        let relative = relative.trim_start_matches('/');
+        match self.root_of_client_path(relative) {
+            Some(root) if root.name.is_empty() => root.resolve(relative),
+            Some(root) => root.resolve(relative.split_once('/').map_or("", |(_, rest)| rest)),
+            None => Err(format!("Not found: {}", relative)),
+        }
+    }
+
+    fn root_of_client_path(&self, relative: &str) -> Option<Root> {
+        let first = relative.split('/').next().unwrap_or_default();
+        let roots = self.roots.read().unwrap();
+        roots
+            .iter()
+            .find(|root| !root.name.is_empty() && root.name == first)
+            .or_else(|| roots.iter().find(|root| root.name.is_empty()))
+            .cloned()
+    }
+
+    // Parses markdown with the server's extensions, for text that isn't a file
+    pub fn parse_text(&self, content: &str) -> Result<Document, String> {
+        parse_with(&self.config, content)
+    }
+
+    // Parses a file's content as what its name says it is: markdown, or
+    // plain text shown preformatted, with its root's settings
+    pub fn parse_file(&self, path: &Path, content: &str) -> Result<Document, String> {
+        let config = self.config_for(path);
+        match config.get().watch.file_types().kind(path) {
+            Some(FileKind::Text) => Ok(Document::preformatted(content)),
+            _ => parse_with(&config, content),
+        }
+    }
+
+    // Replaces the document's includes with the files they name, which have
+    // to be documents in the directory of the root including them. They
+    // don't have to be served themselves: partials in an excluded directory,
+    // or next to the file of a single-file root, can be included.
+    pub fn expand_includes(&self, path: &Path, document: Document) -> Expanded {
+        let root = self.root_of(path);
+        include::expand(document, path, |included| {
+            let Some(root) = root.as_ref().filter(|root| included.starts_with(&root.dir)) else {
+                return Err("not in the same root".to_string());
+            };
+            if !root.config.get().watch.file_types().is_document(included) {
+                return Err("not a markdown or text file".to_string());
+            }
+            // a symlink may point anywhere
+            let real = included.canonicalize().map_err(|e| e.to_string())?;
+            if !real.starts_with(&root.dir) {
+                return Err("not in the same root".to_string());
+            }
+            let content = std::fs::read_to_string(&real).map_err(|e| e.to_string())?;
+            self.parse_file(included, &content)
+        })
+    }
+
+    pub fn render_document(&self, document: &Document) -> String {
+        document.to_html_with(&self.config.get().render)
+    }
+
+    // A file's blocks rendered with its root's options
+    pub fn render_blocks(&self, path: &Path, document: &Document) -> Vec<String> {
+        document.render_blocks(&self.config_for(path).get().render)
+    }
+
+    // Parses and renders markdown with the current configuration
+    pub fn render_text(&self, content: &str) -> Result<String, String> {
+        let document = self.parse_text(content)?;
+        Ok(self.render_document(&document))
+    }
+
+    pub async fn read_file(&self, relative: &str) -> Result<String, String> {
+        let path = self.resolve_document(relative)?;
+        tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| format!("Failed to read file {}: {}", relative, e))
+    }
+
+    // Reads and renders a file given relative to the root
+    pub async fn render_file(&self, relative: &str) -> Result<String, String> {
+        let path = self.resolve_document(relative)?;
+        let document = self.parse_file(&path, &self.read_file(relative).await?)?;
+        let document = self.expand_includes(&path, document).document;
+        Ok(document.to_html_with(&self.config_for(&path).get().render))
+    }
+
+    pub async fn read_source(&self, relative: &str) -> Result<Source, String> {
+        let content = self.read_file(relative).await?;
+        Ok(Source {
+            hash: source_hash(&content),
+            content,
+        })
+    }
+
+    // Writes an edited document. base_hash is the hash the editor loaded
+    // (None to create a new file); if the file on disk no longer matches it,
+    // nothing is written.
+    pub async fn save_file(&self, relative: &str, content: &str, base_hash: Option<&str>) -> Result<String, SaveError> {
+        let path = self.resolve_writable(relative).map_err(SaveError::Invalid)?;
+
+        let _guard = self.save_lock.lock().await;
+        let current_hash = match tokio::fs::read_to_string(&path).await {
+            Ok(current) => Some(source_hash(&current)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(SaveError::Failed(format!("Failed to read file {}: {}", relative, e))),
+        };
+        if current_hash.as_deref() != base_hash {
+            let message = match (&current_hash, base_hash) {
+                (Some(_), None) => format!("{} already exists", relative),
+                (None, Some(_)) => format!("{} was deleted since it was loaded", relative),
+                _ => format!("{} was changed on disk since it was loaded", relative),
+            };
+            return Err(SaveError::Conflict { current_hash, message });
+        }
+
+        tokio::fs::write(&path, content)
+            .await
+            .map_err(|e| SaveError::Failed(format!("Failed to write file {}: {}", relative, e)))?;
+        Ok(source_hash(content))
+    }
+
+    // The render clients should start from: the watcher's cached one, or a
+    // fresh one with version 0 for files the watcher hasn't rendered
+    pub async fn rendered_file(&self, relative: &str) -> Result<RenderedFile, String> {
+        let path = self.resolve_document(relative)?;
+        if let Some(rendered) = self.renders.get(relative) {
+            return Ok(rendered);
+        }
+        let document = self.parse_file(&path, &self.read_file(relative).await?)?;
+        let document = self.expand_includes(&path, document).document;
+        Ok(RenderedFile {
+            version: 0,
+            blocks: self.render_blocks(&path, &document),
+            source_lines: document.source_lines,
+        })
+    }
+
+    // Relative paths of all documents that pass their root's filter
+    pub fn markdown_files(&self) -> Vec<String> {
+        self.roots()
+            .iter()
+            .flat_map(|root| root.documents().into_iter().map(|path| root.client_path(&path)))
+            .collect()
+    }
+}
+
+fn parse_with(config: &SharedConfig, content: &str) -> Result<Document, String> {
+    // the config was validated when it was loaded
+    let parse_options = config.get().markdown.parse_options().unwrap_or_default();
+    parser::parse_markdown(content, &parse_options)
+}
+
+// Content hash in the form clients see it
+pub fn source_hash(content: &str) -> String {
+    format!("{:016x}", watcher::content_hash(content))
+}
@@ -0,0 +1,270 @@
+// Client side of collaborative editing (see src/ot.rs and src/collab.rs).
+// Operations use the ot.js format: a positive number retains that many
+// characters, a negative number deletes that many and a string is inserted.
+
+const isRetain = (c) => typeof c === 'number' && c > 0;
+const isDelete = (c) => typeof c === 'number' && c < 0;
+const isInsert = (c) => typeof c === 'string';
+
+// Appends a component, merging it with the previous one like ot.js does
+function pushComponent(op, c) {
+  if (c === 0 || c === '') {
+    return;
+  }
+  const last = op[op.length - 1];
+  if (isRetain(c) && isRetain(last)) {
+    op[op.length - 1] += c;
+  } else if (isDelete(c) && isDelete(last)) {
+    op[op.length - 1] += c;
+  } else if (isInsert(c) && isInsert(last)) {
+    op[op.length - 1] += c;
+  } else if (isInsert(c) && isDelete(last)) {
+    // inserts go before deletes at the same position
+    if (isInsert(op[op.length - 2])) {
+      op[op.length - 2] += c;
+    } else {
+      op.splice(op.length - 1, 0, c);
+    }
+  } else {
+    op.push(c);
+  }
+}
+
+function applyOperation(op, text) {
+  let index = 0;
+  let result = '';
+  for (const c of op) {
+    if (isRetain(c)) {
+      result += text.slice(index, index + c);
+      index += c;
+    } else if (isInsert(c)) {
+      result += c;
+    } else {
+      index -= c;
+    }
+  }
+  return result;
+}
+
+// a then b as a single operation
+function composeOperations(a, b) {
+  const result = [];
+  let i1 = 0, i2 = 0;
+  let op1 = a[i1++], op2 = b[i2++];
+  while (op1 !== undefined || op2 !== undefined) {
+    if (isDelete(op1)) {
+      pushComponent(result, op1);
+      op1 = a[i1++];
+    } else if (isInsert(op2)) {
+      pushComponent(result, op2);
+      op2 = b[i2++];
+    } else if (op1 === undefined || op2 === undefined) {
+      throw new Error('Cannot compose operations of different lengths');
+    } else if (isRetain(op1) && isRetain(op2)) {
+      const n = Math.min(op1, op2);
+      pushComponent(result, n);
+      op1 = op1 > n ? op1 - n : a[i1++];
+      op2 = op2 > n ? op2 - n : b[i2++];
+    } else if (isInsert(op1) && isDelete(op2)) {
+      const n = Math.min(op1.length, -op2);
+      op1 = op1.length > n ? op1.slice(n) : a[i1++];
+      op2 = -op2 > n ? op2 + n : b[i2++];
+    } else if (isInsert(op1) && isRetain(op2)) {
+      const n = Math.min(op1.length, op2);
+      pushComponent(result, op1.slice(0, n));
+      op1 = op1.length > n ? op1.slice(n) : a[i1++];
+      op2 = op2 > n ? op2 - n : b[i2++];
+    } else {
+      // retain then delete
+      const n = Math.min(op1, -op2);
+      pushComponent(result, -n);
+      op1 = op1 > n ? op1 - n : a[i1++];
+      op2 = -op2 > n ? op2 + n : b[i2++];
+    }
+  }
+  return result;
+}
+
+// [a', b'] such that a then b' equals b then a'; a's inserts go first
+function transformOperations(a, b) {
+  const aPrime = [], bPrime = [];
+  let i1 = 0, i2 = 0;
+  let op1 = a[i1++], op2 = b[i2++];
+  while (op1 !== undefined || op2 !== undefined) {
+    if (isInsert(op1)) {
+      pushComponent(aPrime, op1);
+      pushComponent(bPrime, op1.length);
+      op1 = a[i1++];
+    } else if (isInsert(op2)) {
+      pushComponent(aPrime, op2.length);
+      pushComponent(bPrime, op2);
+      op2 = b[i2++];
+    } else if (op1 === undefined || op2 === undefined) {
+      throw new Error('Cannot transform operations of different lengths');
+    } else {
+      const n = Math.min(Math.abs(op1), Math.abs(op2));
+      if (isRetain(op1) && isRetain(op2)) {
+        pushComponent(aPrime, n);
+        pushComponent(bPrime, n);
+      } else if (isDelete(op1) && isRetain(op2)) {
+        pushComponent(aPrime, -n);
+      } else if (isRetain(op1) && isDelete(op2)) {
+        pushComponent(bPrime, -n);
+      }
+      op1 = Math.abs(op1) > n ? op1 - Math.sign(op1) * n : a[i1++];
+      op2 = Math.abs(op2) > n ? op2 - Math.sign(op2) * n : b[i2++];
+    }
+  }
+  return [aPrime, bPrime];
+}
+
+// Where a position ends up after the operation
+function transformIndex(op, index) {
+  let position = 0;
+  let newIndex = index;
+  for (const c of op) {
+    if (position > index) {
+      break;
+    }
+    if (isRetain(c)) {
+      position += c;
+    } else if (isInsert(c)) {
+      newIndex += c.length;
+    } else {
+      newIndex -= Math.min(-c, index - position);
+      position -= c;
+    }
+  }
+  return newIndex;
+}
+
+// One edit between the common prefix and suffix of old and new
+function diffOperation(oldText, newText) {
+  let prefix = 0;
+  while (prefix < oldText.length && prefix < newText.length && oldText[prefix] === newText[prefix]) {
+    prefix++;
+  }
+  let suffix = 0;
+  while (
+    suffix < oldText.length - prefix &&
+    suffix < newText.length - prefix &&
+    oldText[oldText.length - 1 - suffix] === newText[newText.length - 1 - suffix]
+  ) {
+    suffix++;
+  }
+  const op = [];
+  pushComponent(op, prefix);
+  pushComponent(op, -(oldText.length - prefix - suffix));
+  pushComponent(op, newText.slice(prefix, newText.length - suffix));
+  pushComponent(op, suffix);
+  return op;
+}
+
+// Keeps a textarea in a collaborative session. At most one operation is in
+// flight; changes made meanwhile are buffered and sent once it's acked.
+class CollabClient {
+  constructor(textarea, send, callbacks) {
+    this.textarea = textarea;
+    this.send = send;
+    this.callbacks = callbacks;
+    this.path = null;
+    this.revision = 0;
+    this.outstanding = null;
+    this.buffer = null;
+    this.text = '';
+    this.peers = new Map();
+    textarea.addEventListener('input', () => this.onInput());
+  }
+
+  joined(message) {
+    this.path = message.path;
+    this.revision = message.revision;
+    this.outstanding = null;
+    this.buffer = null;
+    this.text = message.content;
+    this.textarea.value = message.content;
+    this.peers = new Map(message.peers.map((peer) => [peer.client_id, peer]));
+    this.callbacks.onChange();
+  }
+
+  isSynced() {
+    return this.outstanding === null;
+  }
+
+  onInput() {
+    if (this.path === null) {
+      return;
+    }
+    const op = diffOperation(this.text, this.textarea.value);
+    this.text = this.textarea.value;
+    if (this.outstanding === null) {
+      this.sendOperation(op);
+    } else {
+      this.buffer = this.buffer === null ? op : composeOperations(this.buffer, op);
+    }
+    this.callbacks.onStatus();
+  }
+
+  sendOperation(op) {
+    this.outstanding = op;
+    this.send({ type: 'edit', path: this.path, revision: this.revision, operation: op });
+  }
+
+  acked(message) {
+    this.revision = message.revision;
+    this.outstanding = null;
+    if (this.buffer !== null) {
+      const buffer = this.buffer;
+      this.buffer = null;
+      this.sendOperation(buffer);
+    }
+    this.callbacks.onStatus();
+  }
+
+  remoteEdit(message) {
+    this.revision = message.revision;
+    let op = message.operation;
+    if (this.outstanding !== null) {
+      [this.outstanding, op] = transformOperations(this.outstanding, op);
+    }
+    if (this.buffer !== null) {
+      [this.buffer, op] = transformOperations(this.buffer, op);
+    }
+
+    const { selectionStart, selectionEnd } = this.textarea;
+    this.text = applyOperation(op, this.text);
+    this.textarea.value = this.text;
+    this.textarea.setSelectionRange(transformIndex(op, selectionStart), transformIndex(op, selectionEnd));
+    for (const peer of this.peers.values()) {
+      if (peer.head !== null) {
+        peer.anchor = transformIndex(op, peer.anchor);
+        peer.head = transformIndex(op, peer.head);
+      }
+    }
+    this.callbacks.onChange();
+  }
+
+  // Reports our cursor; skipped while an edit is in flight since the server
+  // couldn't place it yet
+  sendSelection() {
+    if (this.path !== null && this.outstanding === null) {
+      this.send({
+        type: 'selection',
+        path: this.path,
+        revision: this.revision,
+        anchor: this.textarea.selectionStart,
+        head: this.textarea.selectionEnd,
+      });
+    }
+  }
+
+  peerUpdate(message) {
+    this.peers.set(message.peer.client_id, message.peer);
+    this.callbacks.onPeers();
+  }
+
+  peerLeft(message) {
+    this.peers.delete(message.client_id);
+    this.callbacks.onPeers();
+  }
+}